@@ -0,0 +1,368 @@
+use image::codecs::gif::{ GifDecoder, GifEncoder, Repeat };
+use image::codecs::png::PngDecoder;
+use image::error::{ EncodingError, ImageFormatHint };
+use image::{ AnimationDecoder, Delay, DynamicImage, ImageError, ImageFormat, ImageResult, RgbaImage };
+use std::borrow::Cow;
+use std::io::{ self, Cursor, Write };
+use crate::binary;
+use crate::decode::{ self, DecodeLimits, DecodeOptions };
+use crate::encode::{ self, Container, EncodeOptions };
+use crate::error::VedError;
+use crate::filter::Filtering;
+use crate::quantize;
+use crate::sample::SampleType;
+use crate::stream::VedEncoder;
+
+//ANCHOR - Animation
+/// What a frame leaves behind once its delay is over: the picture the next
+/// frame is stored as changes to, and what it shows where it keeps the
+/// pixels of that picture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Disposal {
+    /// The frame itself.
+    #[default]
+    Keep,
+    /// Transparent black.
+    Background,
+    /// The picture this frame was stored as changes to.
+    Previous,
+}
+
+impl Disposal {
+    /// Name of the disposal.
+    pub fn name(self) -> &'static str {
+        match self {
+            Disposal::Keep => "keep",
+            Disposal::Background => "background",
+            Disposal::Previous => "previous",
+        }
+    }
+
+    // Id of the disposal in a binary file.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Disposal::Keep => 0,
+            Disposal::Background => 1,
+            Disposal::Previous => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Disposal> {
+        match id {
+            0 => Some(Disposal::Keep),
+            1 => Some(Disposal::Background),
+            2 => Some(Disposal::Previous),
+            _ => None,
+        }
+    }
+}
+
+/// One frame of an animation: the whole picture, however little of it
+/// changed, shown for `delay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay: Delay,
+    pub disposal: Disposal,
+}
+
+impl AnimationFrame {
+    /// A frame shown for `delay` that the next frame is drawn over.
+    pub fn new(image: RgbaImage, delay: Delay) -> AnimationFrame {
+        AnimationFrame { image, delay, disposal: Disposal::Keep }
+    }
+}
+
+// The picture the frame after `frame` is stored as changes to, given the
+// one `frame` was stored against.
+pub(crate) fn next_reference<'a>(frame: &'a [u8], reference: Cow<'a, [u8]>, disposal: Disposal) -> Cow<'a, [u8]> {
+    match disposal {
+        Disposal::Keep => Cow::Borrowed(frame),
+        Disposal::Background => Cow::Owned(vec![0; frame.len()]),
+        Disposal::Previous => reference,
+    }
+}
+
+//ANCHOR - Encode
+/// Encode the frames of an animation into a binary .ved file. Every frame
+/// has to be as large as the first; they share one palette, and each frame
+/// after the first only stores the rows that differ from the picture the
+/// disposal of the frame before leaves. Animations are always written in
+/// the binary container with 8-bit samples and unfiltered rows, whatever
+/// `options` asks for; colors are quantized across all frames at once.
+pub fn encode_animation<W: Write>(frames: &[AnimationFrame], writer: W, options: &EncodeOptions) -> io::Result<W> {
+    let first = frames.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "animation has no frames"))?;
+    let (width, height) = first.image.dimensions();
+    if frames.iter().any(|frame| frame.image.dimensions() != (width, height)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frames differ in size"));
+    }
+    let options = EncodeOptions {
+        container: Container::Binary,
+        filtering: Filtering::Off,
+        sample: Some(SampleType::U8),
+        ..options.clone()
+    };
+    // The frames are looked at as one tall image, so that they get one
+    // color mode and one palette.
+    let mut pixels = Vec::with_capacity(first.image.len() * frames.len());
+    for frame in frames {
+        pixels.extend_from_slice(&frame.image);
+    }
+    let stack = u32::try_from(frames.len())
+        .ok()
+        .and_then(|count| height.checked_mul(count))
+        .and_then(|stack_height| RgbaImage::from_raw(width, stack_height, pixels))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "animation is too tall to encode"))?;
+    let stack = match &options.quantize {
+        Some(quantize) => quantize::quantize(&DynamicImage::ImageRgba8(stack), quantize),
+        None => stack,
+    };
+    // Only store alpha when some pixel is not fully opaque.
+    let has_alpha = stack.pixels().any(|pixel| pixel[3] != 255);
+    let (stack, mode) = encode::fit_colors(Cow::Owned(stack), has_alpha, SampleType::U8, &options)?;
+    let index_bits = mode.index_bits().unwrap_or(0);
+    let layout = mode.layout(encode::is_gray(&stack), has_alpha);
+    let palette = encode::palette_of(&stack, width, layout, index_bits, &options);
+
+    let options = EncodeOptions { color_mode: Some(mode), ..options };
+    let timing: Vec<_> = frames.iter().map(|frame| (frame.delay, frame.disposal)).collect();
+    let mut encoder = VedEncoder::with_frames(writer, width, height, has_alpha, &palette, &options, &timing)?;
+    let (pixels, frame_len) = (stack.as_raw(), first.image.len());
+    let strip_len = width as usize * 4 * encode::STRIP_ROWS as usize;
+    let mut reference = Cow::Owned(vec![0; frame_len]);
+    for (i, source) in frames.iter().enumerate() {
+        let frame = &pixels[i * frame_len..(i + 1) * frame_len];
+        if i == 0 {
+            for strip in frame.chunks(strip_len.max(1)) {
+                encoder.write_strip(strip)?;
+            }
+        } else {
+            encoder.write_frame(frame, &reference)?;
+        }
+        reference = next_reference(frame, reference, source.disposal);
+    }
+    encoder.finish()
+}
+
+//ANCHOR - Decode
+/// Decode every frame of a .ved file. A still image, or a text file, is a
+/// single frame with no delay.
+pub fn decode_animation(bytes: &[u8], limits: DecodeLimits) -> Result<Vec<AnimationFrame>, VedError> {
+    if binary::is_binary(bytes) {
+        return binary::decode_frames(bytes, &limits);
+    }
+    let (img, _) = decode::decode_bytes_with(bytes, &DecodeOptions { lenient: false, limits })?;
+    Ok(vec![AnimationFrame::new(img.to_rgba8(), Delay::from_numer_denom_ms(0, 1))])
+}
+
+//ANCHOR - GIF and APNG
+/// The frames of an animated GIF or PNG, each as the whole picture shown
+/// at that point, or None for a file of any other format or a PNG that is
+/// not animated. `image` already applies the disposal of the source frames
+/// and places them on the full canvas, so every frame keeps.
+pub fn from_image(bytes: &[u8]) -> ImageResult<Option<Vec<AnimationFrame>>> {
+    let frames = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    frames
+        .map(|frame| {
+            let frame = frame?;
+            let delay = frame.delay();
+            Ok(AnimationFrame::new(frame.into_buffer(), delay))
+        })
+        .collect::<ImageResult<_>>()
+        .map(Some)
+}
+
+/// Write the frames as a GIF that loops forever.
+pub fn to_gif<W: Write>(frames: &[AnimationFrame], writer: W) -> ImageResult<()> {
+    let mut encoder = GifEncoder::new(writer);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames.iter().map(|frame| image::Frame::from_parts(frame.image.clone(), 0, 0, frame.delay)))
+}
+
+/// Write the frames as an APNG that loops forever. Each frame replaces the
+/// whole picture, and keeps its disposal.
+pub fn to_apng<W: Write>(frames: &[AnimationFrame], writer: W) -> ImageResult<()> {
+    let (width, height) = frames.first().map_or((0, 0), |frame| frame.image.dimensions());
+    let failed = |error: png::EncodingError| {
+        ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Png), error))
+    };
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0).map_err(failed)?;
+    let mut writer = encoder.write_header().map_err(failed)?;
+    for frame in frames {
+        let (numer, denom) = apng_delay(frame.delay);
+        writer.set_frame_delay(numer, denom).map_err(failed)?;
+        writer.set_blend_op(png::BlendOp::Source).map_err(failed)?;
+        writer.set_dispose_op(match frame.disposal {
+            Disposal::Keep => png::DisposeOp::None,
+            Disposal::Background => png::DisposeOp::Background,
+            Disposal::Previous => png::DisposeOp::Previous,
+        }).map_err(failed)?;
+        writer.write_image_data(&frame.image).map_err(failed)?;
+    }
+    writer.finish().map_err(failed)
+}
+
+// A delay as the seconds of an APNG frame, a ratio of 16-bit numbers:
+// exact when it fits, else to the nearest millisecond as far as that goes.
+fn apng_delay(delay: Delay) -> (u16, u16) {
+    let (numer, denom) = delay.numer_denom_ms();
+    let (numer, denom) = (u64::from(numer), u64::from(denom) * 1000);
+    let gcd = gcd(numer, denom);
+    match (u16::try_from(numer / gcd), u16::try_from(denom / gcd)) {
+        (Ok(numer), Ok(denom)) => (numer, denom),
+        _ => (u16::try_from(numer / (denom / 1000)).unwrap_or(u16::MAX), 1000),
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use super::*;
+    use crate::compression::Compression;
+
+    const WIDTH: u32 = 31;
+    const HEIGHT: u32 = 90;
+
+    // Frames of a small scene where each frame moves a band of rows, over a
+    // background with runs and a handful of colors.
+    fn frames() -> Vec<AnimationFrame> {
+        let disposals = [Disposal::Keep, Disposal::Background, Disposal::Previous, Disposal::Keep];
+        disposals
+            .iter()
+            .enumerate()
+            .map(|(i, &disposal)| {
+                let i = i as u32;
+                let image = RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+                    if (20 * i..20 * i + 12).contains(&y) && x > i {
+                        Rgba([200, (x * 8) as u8, (i * 60) as u8, 255])
+                    } else {
+                        Rgba([(x / 8 * 40) as u8, (y / 10 * 20) as u8, 90, if y % 3 == 0 { 128 } else { 255 }])
+                    }
+                });
+                AnimationFrame { image, delay: Delay::from_numer_denom_ms(100 + i * 10, 3), disposal }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn disposal_picks_the_next_reference() {
+        let (frame, reference) = ([1, 2, 3, 4], [5, 6, 7, 8]);
+        let next = |disposal| next_reference(&frame, Cow::Borrowed(&reference[..]), disposal).into_owned();
+        assert_eq!(next(Disposal::Keep), frame);
+        assert_eq!(next(Disposal::Background), [0; 4]);
+        assert_eq!(next(Disposal::Previous), reference);
+    }
+
+    #[test]
+    fn animations_round_trip() {
+        let frames = frames();
+        for (checksums, compression) in
+            [(false, Compression::None), (true, Compression::None), (false, Compression::Deflate), (true, Compression::Deflate)]
+        {
+            let options = EncodeOptions { checksums, compression, ..EncodeOptions::default() };
+            let bytes = encode_animation(&frames, Vec::new(), &options).unwrap();
+            assert_eq!(decode_animation(&bytes, DecodeLimits::default()).unwrap(), frames, "{:?}", options);
+            assert_eq!(decode::read_info(&bytes).unwrap().frames, frames.len());
+            // Still image decoders see the first frame.
+            assert_eq!(decode::decode_bytes(&bytes).unwrap().to_rgba8(), frames[0].image);
+        }
+    }
+
+    #[test]
+    fn later_frames_only_store_what_changed() {
+        let first = frames().swap_remove(0);
+        let still = encode_animation(std::slice::from_ref(&first), Vec::new(), &EncodeOptions::default()).unwrap();
+        let mut changed = first.clone();
+        changed.image.put_pixel(3, 40, Rgba([1, 2, 3, 255]));
+        let repeated = [first.clone(), first.clone(), changed];
+        let bytes = encode_animation(&repeated, Vec::new(), &EncodeOptions::default()).unwrap();
+        // An unchanged frame is an empty record, and one changed pixel a
+        // single short row.
+        assert!(bytes.len() < still.len() + 40, "{} against {}", bytes.len(), still.len());
+        assert_eq!(decode_animation(&bytes, DecodeLimits::default()).unwrap(), repeated);
+    }
+
+    #[test]
+    fn bad_animations_are_refused() {
+        let options = EncodeOptions::default();
+        assert!(encode_animation(&[], Vec::new(), &options).is_err());
+        let mut frames = frames();
+        frames[1].image = RgbaImage::new(WIDTH, HEIGHT + 1);
+        assert!(encode_animation(&frames, Vec::new(), &options).is_err());
+    }
+
+    #[test]
+    fn apng_keeps_frames_and_delays() {
+        let frames = frames();
+        let mut apng = Vec::new();
+        to_apng(&frames, &mut apng).unwrap();
+        let read = from_image(&apng).unwrap().unwrap();
+        assert_eq!(read.len(), frames.len());
+        // Every frame replaces the whole picture, so disposal does not
+        // change what is shown.
+        for (read, frame) in read.iter().zip(&frames) {
+            assert_eq!(read.image, frame.image);
+            assert_eq!(read.delay, frame.delay);
+        }
+    }
+
+    #[test]
+    fn gif_keeps_frames() {
+        // Opaque frames of few colors, which a GIF holds exactly.
+        let frames: Vec<AnimationFrame> = frames()
+            .into_iter()
+            .map(|mut frame| {
+                frame.image.pixels_mut().for_each(|pixel| pixel[3] = 255);
+                frame.delay = Delay::from_numer_denom_ms(50, 1);
+                frame
+            })
+            .collect();
+        let mut gif = Vec::new();
+        to_gif(&frames, &mut gif).unwrap();
+        let read = from_image(&gif).unwrap().unwrap();
+        assert_eq!(read.len(), frames.len());
+        for (read, frame) in read.iter().zip(&frames) {
+            assert_eq!(read.image, frame.image);
+            assert_eq!(read.delay, frame.delay);
+        }
+    }
+
+    #[test]
+    fn other_images_are_not_animations() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(RgbaImage::new(2, 2)).write_to(&mut png, ImageFormat::Png).unwrap();
+        assert!(from_image(png.get_ref()).unwrap().is_none());
+    }
+
+    #[test]
+    fn apng_delays_fit_16_bits() {
+        assert_eq!(apng_delay(Delay::from_numer_denom_ms(1000, 3)), (1, 3));
+        assert_eq!(apng_delay(Delay::from_numer_denom_ms(40, 1)), (1, 25));
+        // Too long for a 16-bit numerator over whole milliseconds.
+        assert_eq!(apng_delay(Delay::from_numer_denom_ms(70_001, 1)), (u16::MAX, 1000));
+        // Exact over 65535 ms, once reduced to seconds.
+        assert_eq!(apng_delay(Delay::from_numer_denom_ms(70_000, 1)), (70, 1));
+        // A denominator too wide to keep goes to the nearest millisecond.
+        assert_eq!(apng_delay(Delay::from_numer_denom_ms(100_000, 70_001)), (1, 1000));
+    }
+}
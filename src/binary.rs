@@ -0,0 +1,1648 @@
+use image::{ Delay, DynamicImage, RgbaImage };
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::io::{ self, Read, Write };
+use crate::animation::{ self, AnimationFrame, Disposal };
+use crate::checksum;
+use crate::compression::Compression;
+use crate::decode::{ self, DecodeLimits, DecodeOptions, DecodeReport, Repair, SizeStats, VedInfo };
+use crate::encode::Op;
+use crate::filter::{ self, Filter };
+use crate::error::VedError;
+use crate::history::{ self, Above, RowOut };
+use crate::metadata::Metadata;
+use crate::sample::SampleType;
+use crate::stream;
+
+//ANCHOR - Binary container
+/*
+  ┌────────────────────────────────────────────────────────────────────────────┐
+  │ The binary .ved file format is as follows:                                 │
+  │ 1. The 8 magic bytes "\x89VED\r\n\x1a\n".                                  │
+  │ 2. A fixed header: version (u8), width (u32), height (u32), channel        │
+  │ layout (u8), flags (u8) and palette size (u32). The low four bits of the   │
+  │ layout are the channels of each color (1 = gray, 2 = gray and alpha, 3 =   │
+  │ rgb, 4 = rgba), the high four bits the size of a palette index in bits     │
+  │ (1, 2, 4 or 8) when every pixel is one, else 0.                            │
+  │ Integers are little-endian. Bits 0-1 of the flags select the compression   │
+  │ of everything after the header: 0 = none, 1 = deflate.                     │
+  │ 3. The palette: palette size colors of one byte per channel.               │
+  │ 4. One record per row: the byte length of the row as a varint, then       │
+  │ the row's ops as varints whose low two bits are the op code:               │
+  │    0 - palette index, stored in the remaining bits.                        │
+  │    1 - literal color, followed by one byte per channel. In a file whose    │
+  │    pixels are all indices, count indices packed high bits first instead,   │
+  │    padded to whole bytes; the remaining bits hold count - 1.               │
+  │    2 - repeat the previous pixel, the remaining bits hold count - 1.       │
+  │    3 - copy from a row above. If bit 2 is clear, copy count pixels from    │
+  │    the same columns of the row above; the bits above it hold count - 1.    │
+  │    If bit 2 is set, the row is the same as row k, counted from 0, held in  │
+  │    the bits above it; this op must be the only one in the row. Rows can    │
+  │    only copy from the ROW_WINDOW rows above them.                          │
+  │ 5. Varints are unsigned LEB128: 7 bits per byte, low bits first.           │
+  │ 6. If bit 2 of the flags is set, a row index follows the last row: the     │
+  │ offset of each row record as a u64, counted from the start of the file     │
+  │ as if it were not compressed.                                              │
+  │ 7. If bit 3 of the flags is set, each row record starts with a filter      │
+  │ byte (0 none, 1 sub, 2 up, 3 average, 4 paeth) and its ops hold the        │
+  │ residuals of that PNG-style predictor instead of the pixels.               │
+  │ 8. If bit 4 of the flags is set, runs span rows: each record holds         │
+  │ RESTART_ROWS rows, fewer in the last one, with a filter byte per row in a  │
+  │ filtered file and then ops covering all of their pixels in order. Repeats  │
+  │ and copies may cross rows, copies read the pixel one row up within the     │
+  │ record, and whole-row copies are not allowed. Records never look at each   │
+  │ other, and the row index holds the offset of each record.                  │
+  │ 9. Bits 5-6 of the flags are the sample type: 0 = 8 bits, 1 = 16 bits,    │
+  │ 2 = 32-bit float. Wider samples are split into byte planes: each row is    │
+  │ stored as width pixels holding the most significant byte of every         │
+  │ sample, then width pixels holding the next byte, and so on. Floats are     │
+  │ split by their bits. Everything above that counts pixels in a row counts   │
+  │ these stored pixels.                                                       │
+  │ 10. If bit 7 of the flags is set, the file has checksums: each record      │
+  │ starts with the CRC-32 of the rest of it as a u32, before any filter       │
+  │ bytes, and the file ends with the XXH64 hash (seed 0) of everything        │
+  │ before it, after the row index, as a u64. Both are taken as if the file    │
+  │ were not compressed.                                                       │
+  │ 11. From version 3 on, the palette is followed by the metadata: its byte   │
+  │ length as a varint, then entries of a type (u8: 0 = text, 1 = EXIF,        │
+  │ 2 = XMP, 3 = ICC profile), a key and a value, each its byte length as a    │
+  │ varint and then its bytes. Only text has a key; keys, text and XMP are     │
+  │ UTF-8. Entries of other types are skipped.                                 │
+  │ 12. From version 4 on, the metadata is followed by the frame table: its    │
+  │ byte length as a varint, then for each frame its delay in milliseconds as  │
+  │ a numerator and a nonzero denominator, both varints, and its disposal (u8: │
+  │ 0 = keep, 1 = background, 2 = previous). An empty table is a still image.  │
+  │ The records above hold the first frame, and each later frame follows them  │
+  │ as a record: its byte length as a varint, the number of runs of rows it    │
+  │ changes as a varint, the rows each run skips after the last one and the    │
+  │ rows it changes as varints, then a row record for each changed row. These  │
+  │ rows are never filtered and never span rows, copies from above read the    │
+  │ same row of the reference and whole-row copies are not allowed. The        │
+  │ reference, which also gives the rows a frame leaves alone, is the frame    │
+  │ before if that keeps, transparent black if it disposes to the background,  │
+  │ and the reference of the frame before if it disposes to the previous       │
+  │ picture. The row index ends with where the first frame ends.               │
+  │                                                                            │
+  └────────────────────────────────────────────────────────────────────────────┘
+ */
+
+/// Bytes every binary .ved file starts with.
+pub const MAGIC: [u8; 8] = *b"\x89VED\r\n\x1a\n";
+
+const OP_INDEX: u64 = 0;
+const OP_LITERAL: u64 = 1;
+const OP_REPEAT: u64 = 2;
+const OP_COPY: u64 = 3;
+// Bit of a copy op marking a copy of a whole earlier row.
+const COPY_ROW: u64 = 0b100;
+
+/// The channels stored for each color. Gray colors are expanded to RGBA
+/// with the gray value in the red, green and blue channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLayout {
+    L,
+    La,
+    Rgb,
+    Rgba,
+}
+
+impl ChannelLayout {
+    /// Number of bytes per color.
+    pub fn channels(self) -> usize {
+        match self {
+            ChannelLayout::L => 1,
+            ChannelLayout::La => 2,
+            ChannelLayout::Rgb => 3,
+            ChannelLayout::Rgba => 4,
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, ChannelLayout::La | ChannelLayout::Rgba)
+    }
+
+    pub fn is_gray(self) -> bool {
+        matches!(self, ChannelLayout::L | ChannelLayout::La)
+    }
+
+    /// Name of the layout in the header of a text file.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChannelLayout::L => "l",
+            ChannelLayout::La => "la",
+            ChannelLayout::Rgb => "rgb",
+            ChannelLayout::Rgba => "rgba",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChannelLayout> {
+        match name {
+            "l" => Some(ChannelLayout::L),
+            "la" => Some(ChannelLayout::La),
+            "rgb" => Some(ChannelLayout::Rgb),
+            "rgba" => Some(ChannelLayout::Rgba),
+            _ => None,
+        }
+    }
+
+    // The layout of colors that are gray or not and have alpha or not.
+    pub(crate) fn of(gray: bool, alpha: bool) -> ChannelLayout {
+        match (gray, alpha) {
+            (true, false) => ChannelLayout::L,
+            (true, true) => ChannelLayout::La,
+            (false, false) => ChannelLayout::Rgb,
+            (false, true) => ChannelLayout::Rgba,
+        }
+    }
+
+    // Number of leading bytes of each RGBA pixel that filters predict:
+    // the alpha byte only when it is stored.
+    pub(crate) fn filtered_channels(self) -> usize {
+        if self.has_alpha() { 4 } else { 3 }
+    }
+
+    // The stored channels of an RGBA color, in its first `channels()` bytes.
+    pub(crate) fn store(self, color: [u8; 4]) -> [u8; 4] {
+        match self {
+            ChannelLayout::L => [color[0], 0, 0, 0],
+            ChannelLayout::La => [color[0], color[3], 0, 0],
+            ChannelLayout::Rgb | ChannelLayout::Rgba => color,
+        }
+    }
+
+    // The RGBA color of stored channels.
+    pub(crate) fn load(self, stored: &[u8]) -> [u8; 4] {
+        match self {
+            ChannelLayout::L => [stored[0], stored[0], stored[0], 255],
+            ChannelLayout::La => [stored[0], stored[0], stored[0], stored[1]],
+            ChannelLayout::Rgb => [stored[0], stored[1], stored[2], 255],
+            ChannelLayout::Rgba => [stored[0], stored[1], stored[2], stored[3]],
+        }
+    }
+
+    // A packed RGBA color as it reads back once stored.
+    pub(crate) fn normalize(self, color: u32) -> u32 {
+        match self {
+            ChannelLayout::L => ((color >> 24) * 0x0101_0100) | 0xFF,
+            ChannelLayout::La => ((color >> 24) * 0x0101_0100) | (color & 0xFF),
+            ChannelLayout::Rgb => color | 0xFF,
+            ChannelLayout::Rgba => color,
+        }
+    }
+
+    fn from_channels(channels: u8) -> Option<ChannelLayout> {
+        match channels {
+            1 => Some(ChannelLayout::L),
+            2 => Some(ChannelLayout::La),
+            3 => Some(ChannelLayout::Rgb),
+            4 => Some(ChannelLayout::Rgba),
+            _ => None,
+        }
+    }
+}
+
+// Whether palette indices can be this many bits: 1, 2, 4 or 8.
+pub(crate) fn is_index_size(bits: u8) -> bool {
+    matches!(bits, 1 | 2 | 4 | 8)
+}
+
+// Number of bytes holding `count` palette indices of `bits` bits.
+pub(crate) fn packed_len(count: usize, bits: u8) -> usize {
+    (count * bits as usize).div_ceil(8)
+}
+
+// The palette indices packed `bits` to an index, high bits first, into
+// `bytes`.
+pub(crate) fn unpack_indices(bytes: &[u8], bits: u8) -> impl Iterator<Item = usize> + Clone + '_ {
+    let mask = ((1u16 << bits) - 1) as u8;
+    bytes
+        .iter()
+        .flat_map(move |&byte| (1..=8 / bits).map(move |i| ((byte >> (8 - i * bits)) & mask) as usize))
+}
+
+/// The fixed header following the magic bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryHeader {
+    pub version: u8,
+    pub width: u32,
+    pub height: u32,
+    pub layout: ChannelLayout,
+    /// Size of a palette index in bits when every pixel is one, else 0.
+    pub index_bits: u8,
+    /// Bits 0-1 hold the compression, see `compression`, bit 2 marks a
+    /// row index, see `has_row_index`, bit 3 filtered rows, see
+    /// `is_filtered`, bit 4 runs that span rows, see `spans_rows`, and bits
+    /// 5-6 the sample type, see `sample`, and bit 7 marks checksums, see
+    /// `has_checksums`.
+    pub flags: u8,
+    pub palette_len: u32,
+}
+
+// Header flag bits holding the compression id.
+const FLAG_COMPRESSION: u8 = 0b0000_0011;
+// Header flag bit marking a row index after the last row.
+pub(crate) const FLAG_ROW_INDEX: u8 = 0b0000_0100;
+// Header flag bit marking rows that start with a filter byte.
+pub(crate) const FLAG_FILTERED: u8 = 0b0000_1000;
+// Header flag bit marking records of several rows whose runs span rows.
+pub(crate) const FLAG_SPANS_ROWS: u8 = 0b0001_0000;
+// Header flag bits holding the sample type id.
+const FLAG_SAMPLE: u8 = 0b0110_0000;
+pub(crate) const SAMPLE_SHIFT: u32 = 5;
+// Header flag bit marking a checksum per record and a hash of the file.
+pub(crate) const FLAG_CHECKSUMS: u8 = 0b1000_0000;
+
+// Size of the checksum at the start of each record and of the hash at the
+// end of a file with checksums.
+pub(crate) const CHECKSUM_LEN: usize = 4;
+pub(crate) const HASH_LEN: usize = 8;
+
+// The oldest binary version still read, the first with metadata and the
+// first with frames.
+const FIRST_VERSION: u32 = 2;
+const METADATA_VERSION: u32 = 3;
+const FRAMES_VERSION: u32 = 4;
+
+/// Number of rows in each record of a file whose runs span rows. Runs
+/// restart at the start of every record, so records decode independently.
+pub const RESTART_ROWS: u32 = 64;
+
+impl BinaryHeader {
+    /// Size in bytes of the header, not counting the magic bytes.
+    pub const SIZE: usize = 15;
+
+    /// Entropy coding of everything after the header.
+    pub fn compression(&self) -> Compression {
+        Compression::from_id(self.flags & FLAG_COMPRESSION).unwrap_or_default()
+    }
+
+    /// Whether the rows are followed by the offset of each row.
+    pub fn has_row_index(&self) -> bool {
+        self.flags & FLAG_ROW_INDEX != 0
+    }
+
+    /// Whether each row starts with the filter its pixels were predicted with.
+    pub fn is_filtered(&self) -> bool {
+        self.flags & FLAG_FILTERED != 0
+    }
+
+    /// Whether each record holds RESTART_ROWS rows whose runs continue
+    /// from one row into the next.
+    pub fn spans_rows(&self) -> bool {
+        self.flags & FLAG_SPANS_ROWS != 0
+    }
+
+    /// Whether each record starts with a checksum and the file ends with a hash.
+    pub fn has_checksums(&self) -> bool {
+        self.flags & FLAG_CHECKSUMS != 0
+    }
+
+    /// Whether the palette is followed by metadata, as from version 3 on.
+    pub fn has_metadata(&self) -> bool {
+        u32::from(self.version) >= METADATA_VERSION
+    }
+
+    /// Whether the metadata is followed by a frame table, and the row index
+    /// by where the first frame ends, as from version 4 on.
+    pub fn has_frames(&self) -> bool {
+        u32::from(self.version) >= FRAMES_VERSION
+    }
+
+    /// Type of each channel sample.
+    pub fn sample(&self) -> SampleType {
+        SampleType::from_id((self.flags & FLAG_SAMPLE) >> SAMPLE_SHIFT).unwrap_or_default()
+    }
+
+    /// Number of pixels stored for each row: the width, times the bytes of
+    /// each sample since every byte plane takes a pixel.
+    pub fn stored_width(&self) -> u32 {
+        self.width * self.sample().bytes() as u32
+    }
+
+    /// Number of row records: one per row, or one per RESTART_ROWS rows
+    /// when runs span rows.
+    pub fn records(&self) -> u32 {
+        if self.spans_rows() { self.height.div_ceil(RESTART_ROWS) } else { self.height }
+    }
+
+    // Number of rows in record `record`.
+    pub(crate) fn record_rows(&self, record: usize) -> usize {
+        if self.spans_rows() {
+            (self.height as usize - record * RESTART_ROWS as usize).min(RESTART_ROWS as usize)
+        } else {
+            1
+        }
+    }
+
+    // Number of bytes following a literal op: one color, or the packed
+    // indices of a file whose pixels are all indices.
+    fn literal_len(&self, op: u64) -> Option<usize> {
+        if self.index_bits == 0 {
+            return Some(self.layout.channels());
+        }
+        let count = usize::try_from((op >> 2) + 1).ok()?;
+        Some(count.checked_mul(self.index_bits.into())?.div_ceil(8))
+    }
+
+    /// Size in bytes of the row index, if there is one.
+    pub fn row_index_len(&self) -> u64 {
+        let entries = self.records() as u64 + u64::from(self.has_frames());
+        if self.has_row_index() { entries * 8 } else { 0 }
+    }
+
+    /// Size in bytes of the checksums of the records and the file hash, if
+    /// there are any.
+    pub fn checksums_len(&self) -> u64 {
+        if self.has_checksums() { (self.records() as u64) * CHECKSUM_LEN as u64 + HASH_LEN as u64 } else { 0 }
+    }
+
+    // Size in bytes of what follows the last record: the row index and hash.
+    pub(crate) fn trailer_len(&self) -> u64 {
+        self.row_index_len() + if self.has_checksums() { HASH_LEN as u64 } else { 0 }
+    }
+
+    // The first row of record `record`.
+    pub(crate) fn record_start(&self, record: usize) -> u32 {
+        if self.spans_rows() { record as u32 * RESTART_ROWS } else { record as u32 }
+    }
+
+    // Summary of a file with this header.
+    pub(crate) fn info(&self) -> VedInfo {
+        VedInfo {
+            version: self.version.into(),
+            width: self.width,
+            height: self.height,
+            alpha: self.layout.has_alpha(),
+            layout: self.layout,
+            index_bits: self.index_bits,
+            sample: self.sample(),
+            palette_len: self.palette_len as usize,
+            rows: self.height as usize,
+            compression: self.compression(),
+            row_index: self.has_row_index(),
+            filtered: self.is_filtered(),
+            spans_rows: self.spans_rows(),
+            checksums: self.has_checksums(),
+            frames: 1,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.version);
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.push(self.index_bits << 4 | self.layout.channels() as u8);
+        out.push(self.flags);
+        out.extend_from_slice(&self.palette_len.to_le_bytes());
+    }
+
+    pub(crate) fn read(reader: &mut Reader) -> Result<BinaryHeader, VedError> {
+        let version = reader.u8()?;
+        if !(FIRST_VERSION..=crate::FORMAT_VERSION).contains(&u32::from(version)) {
+            return Err(VedError::UnsupportedVersion(version.into()));
+        }
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let layout_offset = reader.pos;
+        let layout = reader.u8()?;
+        let (index_bits, layout) = (layout >> 4, ChannelLayout::from_channels(layout & 0xF));
+        let layout = layout.ok_or_else(|| reader.bad(layout_offset, "unknown channel layout"))?;
+        if index_bits != 0 && !is_index_size(index_bits) {
+            return Err(reader.bad(layout_offset, "palette indices must be 1, 2, 4 or 8 bits"));
+        }
+        let flags_offset = reader.pos;
+        let flags = reader.u8()?;
+        if Compression::from_id(flags & FLAG_COMPRESSION).is_none() {
+            return Err(reader.bad(flags_offset, "unknown compression"));
+        }
+        let sample = SampleType::from_id((flags & FLAG_SAMPLE) >> SAMPLE_SHIFT);
+        let sample = sample.ok_or_else(|| reader.bad(flags_offset, "unknown sample type"))?;
+        if width.checked_mul(sample.bytes() as u32).is_none() {
+            return Err(reader.bad(flags_offset, "image is too wide for its sample type"));
+        }
+        let palette_len_offset = reader.pos;
+        let palette_len = reader.u32()?;
+        if index_bits != 0 && u64::from(palette_len) > 1 << index_bits {
+            return Err(reader.bad(palette_len_offset, "palette has more colors than its indices can reach"));
+        }
+        Ok(BinaryHeader { version, width, height, layout, index_bits, flags, palette_len })
+    }
+}
+
+/// Whether the bytes start with the binary magic bytes.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Append an unsigned LEB128 varint.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Number of bytes `write_varint` takes for a value.
+pub(crate) fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+// Decode an unsigned LEB128 varint from successive bytes, or None if it
+// overflows 64 bits.
+pub(crate) fn read_varint<E>(mut next_byte: impl FnMut() -> Result<u8, E>) -> Result<Option<u64>, E> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = next_byte()?;
+        let bits = u64::from(byte & 0x7F);
+        if shift == 63 && bits > 1 {
+            break;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+// Cursor over the bytes of a binary file, reporting offsets on failure.
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bad(&self, offset: usize, message: &str) -> VedError {
+        VedError::BadBinary { offset, message: message.to_string() }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VedError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or(VedError::UnexpectedEof { offset: self.bytes.len() })?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, VedError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, VedError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, VedError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, VedError> {
+        let start = self.pos;
+        read_varint(|| self.u8())?.ok_or_else(|| self.bad(start, "varint overflows 64 bits"))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+//ANCHOR - Write
+// Write the magic bytes and header.
+pub(crate) fn write_header<W: Write>(writer: &mut W, header: &BinaryHeader) -> io::Result<()> {
+    let mut output = MAGIC.to_vec();
+    header.write(&mut output);
+    writer.write_all(&output)
+}
+
+// Write the palette, which starts the possibly compressed part of the file.
+pub(crate) fn write_palette<W: Write>(
+    writer: &mut W,
+    palette: &[[u8; 4]],
+    layout: ChannelLayout
+) -> io::Result<()> {
+    let mut output = Vec::with_capacity(palette.len() * layout.channels());
+    for &color in palette {
+        output.extend_from_slice(&layout.store(color)[..layout.channels()]);
+    }
+    writer.write_all(&output)
+}
+
+// The metadata section: its length, then every entry.
+pub(crate) fn metadata_section(metadata: &[Metadata]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    for entry in metadata {
+        let (id, key, value) = entry.parts();
+        entries.push(id);
+        write_varint(&mut entries, key.len() as u64);
+        entries.extend_from_slice(key);
+        write_varint(&mut entries, value.len() as u64);
+        entries.extend_from_slice(value);
+    }
+    let mut out = Vec::with_capacity(entries.len() + varint_len(entries.len() as u64));
+    write_varint(&mut out, entries.len() as u64);
+    out.extend_from_slice(&entries);
+    out
+}
+
+// The delay and disposal of a frame, as its entry in the frame table has them.
+pub(crate) type Timing = (Delay, Disposal);
+
+// The frame table: the delay and disposal of each frame, or nothing for a
+// still image.
+pub(crate) fn frame_table(frames: &[Timing]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    for &(delay, disposal) in frames {
+        let (numer, denom) = delay.numer_denom_ms();
+        write_varint(&mut entries, numer.into());
+        write_varint(&mut entries, denom.into());
+        entries.push(disposal.id());
+    }
+    let mut out = Vec::with_capacity(entries.len() + varint_len(entries.len() as u64));
+    write_varint(&mut out, entries.len() as u64);
+    out.extend_from_slice(&entries);
+    out
+}
+
+// Append the record of a frame after the first: its runs of changed rows,
+// each as the rows skipped before it and its number of rows, then the row
+// records of the changed rows.
+pub(crate) fn write_frame(runs: &[(u32, u32)], rows: &[Vec<u8>], out: &mut Vec<u8>) {
+    let mut contents = Vec::new();
+    write_varint(&mut contents, runs.len() as u64);
+    for &(skip, count) in runs {
+        write_varint(&mut contents, skip.into());
+        write_varint(&mut contents, count.into());
+    }
+    for row in rows {
+        contents.extend_from_slice(row);
+    }
+    write_varint(out, contents.len() as u64);
+    out.extend_from_slice(&contents);
+}
+
+// Append one row record: its length, then its checksum in a file with
+// checksums, the filter of each of its rows in a filtered file, and its
+// ops. `index_bits` is the size of the packed indices of a file whose
+// pixels are all indices.
+pub(crate) fn write_row(
+    ops: &[Op],
+    layout: ChannelLayout,
+    index_bits: u8,
+    filters: &[Filter],
+    checksum: bool,
+    out: &mut Vec<u8>
+) {
+    let channels = layout.channels();
+    let varint = |op: Op| match op {
+        Op::Index(index) => (u64::from(index) << 2) | OP_INDEX,
+        Op::Literal(_) => OP_LITERAL,
+        Op::Packed(count) => ((count - 1) << 2) | OP_LITERAL,
+        Op::Repeat(count) => ((count - 1) << 2) | OP_REPEAT,
+        Op::Above(count) => ((count - 1) << 3) | OP_COPY,
+        Op::Row(k) => (k << 3) | COPY_ROW | OP_COPY,
+    };
+    let mut len = 0;
+    let mut i = 0;
+    while i < ops.len() {
+        len += varint_len(varint(ops[i]));
+        i += match ops[i] {
+            Op::Literal(_) => {
+                len += channels;
+                1
+            }
+            Op::Packed(count) => {
+                len += packed_len(count as usize, index_bits);
+                1 + count as usize
+            }
+            _ => 1,
+        };
+    }
+    let checksum_len = if checksum { CHECKSUM_LEN } else { 0 };
+    write_varint(out, (checksum_len + filters.len() + len) as u64);
+    let start = out.len();
+    out.resize(start + checksum_len, 0);
+    out.extend(filters.iter().map(|filter| filter.id()));
+    let mut ops = ops.iter();
+    while let Some(&op) = ops.next() {
+        write_varint(out, varint(op));
+        match op {
+            Op::Literal(color) => out.extend_from_slice(&layout.store(color.to_be_bytes())[..channels]),
+            Op::Packed(count) => {
+                let (mut byte, mut used) = (0, 0);
+                for op in ops.by_ref().take(count as usize) {
+                    let Op::Index(index) = op else { unreachable!("packed indices are index ops") };
+                    byte |= (*index as u8) << (8 - used - index_bits);
+                    used += index_bits;
+                    if used == 8 {
+                        out.push(byte);
+                        (byte, used) = (0, 0);
+                    }
+                }
+                if used > 0 {
+                    out.push(byte);
+                }
+            }
+            _ => {}
+        }
+    }
+    if checksum {
+        let crc = checksum::crc32(&out[start + CHECKSUM_LEN..]);
+        out[start..start + CHECKSUM_LEN].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+// Write the row index: where each row record starts.
+pub(crate) fn write_row_index<W: Write>(writer: &mut W, offsets: &[u64]) -> io::Result<()> {
+    let output: Vec<u8> = offsets.iter().flat_map(|offset| offset.to_le_bytes()).collect();
+    writer.write_all(&output)
+}
+
+//ANCHOR - Read
+// Check a row index against where the rows actually start.
+pub(crate) fn check_row_index(index: &[u8], start: usize, offsets: &[u64]) -> Result<(), VedError> {
+    let mut reader = Reader { bytes: index, pos: 0 };
+    for &offset in offsets {
+        let entry = reader.pos;
+        if reader.u64().map_err(|_| VedError::UnexpectedEof { offset: start + index.len() })? != offset {
+            return Err(VedError::BadBinary {
+                offset: start + entry,
+                message: "row index does not match the rows".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Turn palette bytes into RGBA colors.
+pub(crate) fn parse_palette(bytes: &[u8], layout: ChannelLayout) -> Vec<[u8; 4]> {
+    bytes.chunks_exact(layout.channels()).map(|color| layout.load(color)).collect()
+}
+
+// Offset of the palette, the first byte after the header.
+pub(crate) const BODY_OFFSET: usize = MAGIC.len() + BinaryHeader::SIZE;
+
+// Read the header and decompress the rest of the file if needed. Offsets
+// into the returned bytes, and so in errors, are positions in the
+// uncompressed file. Decompressing stops with an error once the data
+// takes more than `limits.max_memory` bytes. When `lenient`, corrupt or
+// truncated compressed data gives what decompressed before the damage.
+pub(crate) fn uncompressed<'a>(
+    bytes: &'a [u8],
+    limits: &DecodeLimits,
+    lenient: bool
+) -> Result<(BinaryHeader, Cow<'a, [u8]>), VedError> {
+    let header = BinaryHeader::read(&mut (Reader { bytes, pos: MAGIC.len() }))?;
+    match header.compression() {
+        Compression::None => Ok((header, Cow::Borrowed(bytes))),
+        compression => {
+            let mut data = bytes[..BODY_OFFSET].to_vec();
+            let read = compression
+                .reader(&bytes[BODY_OFFSET..])
+                .take(limits.max_memory.saturating_add(1))
+                .read_to_end(&mut data);
+            match read {
+                Err(error) if !lenient => {
+                    return Err(VedError::BadBinary {
+                        offset: BODY_OFFSET,
+                        message: format!("corrupt {} data: {}", compression.name(), error),
+                    });
+                }
+                _ => {}
+            }
+            limits.check_memory("decompressed size", (data.len() - BODY_OFFSET) as u64)?;
+            Ok((header, Cow::Owned(data)))
+        }
+    }
+}
+
+// Read the palette, leaving the reader at the first row.
+fn read_palette(reader: &mut Reader, header: &BinaryHeader) -> Result<Vec<[u8; 4]>, VedError> {
+    let palette_bytes = (header.palette_len as usize).checked_mul(header.layout.channels());
+    let palette_bytes = palette_bytes.ok_or(VedError::UnexpectedEof { offset: reader.bytes.len() })?;
+    Ok(parse_palette(reader.take(palette_bytes)?, header.layout))
+}
+
+// Read the metadata of a file that has it, leaving the reader at the first row.
+fn take_metadata(reader: &mut Reader, header: &BinaryHeader) -> Result<Vec<Metadata>, VedError> {
+    if !header.has_metadata() {
+        return Ok(Vec::new());
+    }
+    let len = usize::try_from(reader.varint()?).map_err(|_| VedError::UnexpectedEof { offset: reader.bytes.len() })?;
+    let start = reader.pos;
+    parse_metadata(reader.take(len)?, start)
+}
+
+// Parse the entries of the metadata section found at `offset`.
+pub(crate) fn parse_metadata(entries: &[u8], offset: usize) -> Result<Vec<Metadata>, VedError> {
+    let mut reader = Reader { bytes: entries, pos: 0 };
+    let mut metadata = Vec::new();
+    while !reader.is_empty() {
+        let entry = reader.pos;
+        let bad = |message: &str| VedError::BadBinary { offset: offset + entry, message: message.to_string() };
+        let (id, key, value) = read_entry(&mut reader).map_err(|error| match error {
+            VedError::BadBinary { message, .. } => bad(&message),
+            _ => bad("metadata entry runs past the end of the metadata"),
+        })?;
+        metadata.extend(Metadata::from_parts(id, key, value).map_err(bad)?);
+    }
+    Ok(metadata)
+}
+
+// Read the type, key and value of one metadata entry.
+fn read_entry<'a>(reader: &mut Reader<'a>) -> Result<(u8, &'a [u8], &'a [u8]), VedError> {
+    let id = reader.u8()?;
+    let mut take_value = || {
+        let len = usize::try_from(reader.varint()?).unwrap_or(usize::MAX);
+        reader.take(len)
+    };
+    Ok((id, take_value()?, take_value()?))
+}
+
+// Read the frame table of a file that has one, leaving the reader at the
+// first row.
+fn take_frames(reader: &mut Reader, header: &BinaryHeader) -> Result<Vec<Timing>, VedError> {
+    if !header.has_frames() {
+        return parse_frames(&[], reader.pos);
+    }
+    let len = usize::try_from(reader.varint()?).map_err(|_| VedError::UnexpectedEof { offset: reader.bytes.len() })?;
+    let start = reader.pos;
+    parse_frames(reader.take(len)?, start)
+}
+
+// Parse the frame table found at `offset` into the delay and disposal of
+// each frame, or of the one frame of a still image.
+pub(crate) fn parse_frames(table: &[u8], offset: usize) -> Result<Vec<Timing>, VedError> {
+    let mut reader = Reader { bytes: table, pos: 0 };
+    let mut frames = Vec::new();
+    while !reader.is_empty() {
+        let entry = reader.pos;
+        let bad = |message: &str| VedError::BadBinary { offset: offset + entry, message: message.to_string() };
+        frames.push(read_frame_entry(&mut reader).map_err(|error| match error {
+            VedError::BadBinary { message, .. } => bad(&message),
+            _ => bad("frame entry runs past the end of the frame table"),
+        })?);
+    }
+    if frames.is_empty() {
+        frames.push((Delay::from_numer_denom_ms(0, 1), Disposal::Keep));
+    }
+    Ok(frames)
+}
+
+// Read the delay and disposal of one frame.
+fn read_frame_entry(reader: &mut Reader) -> Result<Timing, VedError> {
+    let (numer, denom) = (reader.varint()?, reader.varint()?);
+    let delay = match (u32::try_from(numer), u32::try_from(denom)) {
+        (Ok(numer), Ok(denom)) if denom > 0 => Delay::from_numer_denom_ms(numer, denom),
+        _ => return Err(reader.bad(0, "frame delay is not a ratio of 32-bit numbers")),
+    };
+    let disposal = Disposal::from_id(reader.u8()?).ok_or_else(|| reader.bad(0, "unknown disposal"))?;
+    Ok((delay, disposal))
+}
+
+// Read the metadata and frame table of a binary file, decompressing only
+// as far as their end.
+fn read_front(bytes: &[u8]) -> Result<(BinaryHeader, Vec<Metadata>, Vec<Timing>), VedError> {
+    let header = BinaryHeader::read(&mut (Reader { bytes, pos: MAGIC.len() }))?;
+    if !header.has_metadata() {
+        return Ok((header, Vec::new(), parse_frames(&[], BODY_OFFSET)?));
+    }
+    let mut reader = header.compression().reader(bytes.get(BODY_OFFSET..).unwrap_or_default());
+    let palette_len = (header.palette_len as usize).saturating_mul(header.layout.channels());
+    let mut palette = Vec::new();
+    stream::read_exact_or_eof(&mut reader, palette_len, &mut palette).map_err(|error| match error {
+        VedError::UnexpectedEof { offset } => VedError::UnexpectedEof { offset: BODY_OFFSET + offset },
+        error => error,
+    })?;
+    let mut offset = BODY_OFFSET + palette_len;
+    let mut record = Vec::new();
+    let start = stream::read_record(&mut reader, &mut offset, &mut record, &DecodeLimits::default())?;
+    let metadata = parse_metadata(&record, start)?;
+    if !header.has_frames() {
+        return Ok((header, metadata, parse_frames(&[], offset)?));
+    }
+    let start = stream::read_record(&mut reader, &mut offset, &mut record, &DecodeLimits::default())?;
+    let frames = parse_frames(&record, start)?;
+    Ok((header, metadata, frames))
+}
+
+// Read the metadata of a binary file.
+pub(crate) fn read_metadata(bytes: &[u8]) -> Result<Vec<Metadata>, VedError> {
+    Ok(read_front(bytes)?.1)
+}
+
+// Read the header of a binary file, and its frame table to count the frames.
+pub(crate) fn read_info(bytes: &[u8]) -> Result<VedInfo, VedError> {
+    let (header, _, frames) = read_front(bytes)?;
+    Ok(VedInfo { frames: frames.len(), ..header.info() })
+}
+
+// Measure how much each stage of a binary file takes.
+pub(crate) fn read_stats(bytes: &[u8]) -> Result<SizeStats, VedError> {
+    let (header, data) = uncompressed(bytes, &DecodeLimits::default(), false)?;
+    let mut reader = Reader { bytes: &data, pos: BODY_OFFSET };
+    read_palette(&mut reader, &header)?;
+    let palette = (reader.pos - BODY_OFFSET) as u64;
+    take_metadata(&mut reader, &header)?;
+    let metadata = (reader.pos - BODY_OFFSET) as u64 - palette;
+    let table_start = reader.pos;
+    let count = take_frames(&mut reader, &header)?.len();
+    let mut frames = (reader.pos - table_start) as u64;
+    let rows_start = reader.pos;
+    // The frames after the first follow its records.
+    for _ in 0..header.records() {
+        skip_record(&mut reader)?;
+    }
+    let frames_start = reader.pos;
+    for _ in 1..count {
+        skip_record(&mut reader)?;
+    }
+    let later = (reader.pos - frames_start) as u64;
+    frames += later;
+    let fixed = header.row_index_len() + header.checksums_len() + later;
+    Ok(SizeStats {
+        raw: decode::raw_len(header.stored_width(), header.height, header.layout, header.index_bits),
+        header: BODY_OFFSET as u64,
+        palette,
+        metadata,
+        rows: ((data.len() - rows_start) as u64).saturating_sub(fixed),
+        index: header.row_index_len(),
+        checksums: header.checksums_len(),
+        frames,
+        file: bytes.len() as u64,
+    })
+}
+
+// Skip a length-prefixed record, returning its contents.
+fn skip_record<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], VedError> {
+    let len = usize::try_from(reader.varint()?).map_err(|_| VedError::UnexpectedEof { offset: reader.bytes.len() })?;
+    reader.take(len)
+}
+
+// The earliest row that row `y` copies from, if it copies at all. Ops that
+// do not parse are left for `decode_row` to report.
+pub(crate) fn copied_row(row: &[u8], y: usize, header: &BinaryHeader) -> Option<usize> {
+    let mut reader = Reader { bytes: row, pos: 0 };
+    while !reader.is_empty() {
+        let op = reader.varint().ok()?;
+        match op & 3 {
+            OP_LITERAL => {
+                reader.take(header.literal_len(op)?).ok()?;
+            }
+            OP_COPY if op & COPY_ROW != 0 => return usize::try_from(op >> 3).ok(),
+            OP_COPY => return y.checked_sub(1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// What one op expands to.
+enum Token<'a> {
+    // A color, this many times.
+    Pixels([u8; 4], u64),
+    // This many palette indices, packed into the bytes.
+    Packed(&'a [u8], u64),
+    // This many pixels copied from one row up.
+    Above(u64),
+    // The whole row copied from row k.
+    Row(u64),
+}
+
+// Read the next op. `last_color` is the last pixel expanded, which repeat
+// ops repeat; errors are relative to the start of the ops.
+fn next_token<'a>(
+    reader: &mut Reader<'a>,
+    header: &BinaryHeader,
+    palette: &[[u8; 4]],
+    last_color: Option<[u8; 4]>
+) -> Result<Token<'a>, VedError> {
+    let op_offset = reader.pos;
+    let op = reader.varint()?;
+    match op & 3 {
+        OP_INDEX => {
+            let color = usize::try_from(op >> 2).ok().and_then(|index| palette.get(index));
+            let color =
+                color.ok_or_else(|| reader.bad(op_offset, &format!("palette index {} is not defined", op >> 2)))?;
+            Ok(Token::Pixels(*color, 1))
+        }
+        OP_LITERAL if header.index_bits > 0 => {
+            let count = (op >> 2) + 1;
+            let len = header.literal_len(op).ok_or(VedError::UnexpectedEof { offset: reader.bytes.len() })?;
+            let bytes = reader.take(len)?;
+            // The indices are checked here so expanding them cannot fail.
+            let indices = unpack_indices(bytes, header.index_bits).take(count as usize);
+            if let Some(index) = indices.clone().find(|&index| index >= palette.len()) {
+                return Err(reader.bad(op_offset, &format!("palette index {} is not defined", index)));
+            }
+            Ok(Token::Packed(bytes, count))
+        }
+        OP_LITERAL => Ok(Token::Pixels(header.layout.load(reader.take(header.layout.channels())?), 1)),
+        OP_REPEAT => {
+            let color = last_color.ok_or_else(|| reader.bad(op_offset, "repeat before the first pixel of the row"))?;
+            Ok(Token::Pixels(color, (op >> 2) + 1))
+        }
+        _ if op & COPY_ROW != 0 => Ok(Token::Row(op >> 3)),
+        _ => Ok(Token::Above((op >> 3) + 1)),
+    }
+}
+
+// Move an error about the ops of a record that start at `offset` and are
+// `len` bytes long to where it is in the file.
+fn locate(error: VedError, offset: usize, len: usize, what: &str) -> VedError {
+    match error {
+        VedError::BadBinary { offset: pos, message } => VedError::BadBinary { offset: offset + pos, message },
+        VedError::UnexpectedEof { .. } => VedError::BadBinary {
+            offset: offset + len,
+            message: format!("{} ends in the middle of an op", what),
+        },
+        error => error,
+    }
+}
+
+// Expand the ops of one row into `out`. `offset` is where the row starts
+// in the file, for error messages. When `out` does not cover the whole
+// row, expanding stops as soon as it is filled and the rest of the row is
+// not checked; rows that copy from the row above have to be expanded from
+// column 0.
+pub(crate) fn decode_row(
+    row: &[u8],
+    offset: usize,
+    header: &BinaryHeader,
+    palette: &[[u8; 4]],
+    out: RowOut
+) -> Result<(), VedError> {
+    let RowOut { first, pixels: out, above } = out;
+    let width = header.stored_width() as u64;
+    let end = first + out.len() / 4;
+    let partial = first > 0 || (end as u64) < width;
+    let mut filled = 0;
+    let mut reader = Reader { bytes: row, pos: 0 };
+    // Errors point into the whole file, not the row.
+    let located = |error: VedError| locate(error, offset, row.len(), "row");
+
+    let mut last_color = None;
+    while !reader.is_empty() {
+        if partial && filled >= end {
+            return Ok(());
+        }
+        let op_offset = reader.pos;
+        let (color, count) = match next_token(&mut reader, header, palette, last_color).map_err(located)? {
+            Token::Pixels(color, count) => (color, count),
+            Token::Packed(bytes, count) => {
+                if (filled as u64) + count > width {
+                    return Err(located(reader.bad(op_offset, "row is wider than the header width")));
+                }
+                let clamp = |pixel: usize| pixel.clamp(first, end) - first;
+                // Indices left of `out` are skipped.
+                let mut indices = unpack_indices(bytes, header.index_bits);
+                let range = clamp(filled) * 4..clamp(filled + count as usize) * 4;
+                for (pixel, index) in out[range].chunks_exact_mut(4).zip(indices.clone().skip(first.saturating_sub(filled))) {
+                    pixel.copy_from_slice(&palette[index]);
+                }
+                filled += count as usize;
+                last_color = indices.nth(count as usize - 1).map(|index| palette[index]);
+                continue;
+            }
+            Token::Row(k) => {
+                if op_offset != 0 || !reader.is_empty() {
+                    return Err(located(reader.bad(op_offset, "row copy is not the only op of the row")));
+                }
+                let source = usize::try_from(k).ok().and_then(|k| above.get(k));
+                let source =
+                    source.ok_or_else(|| located(reader.bad(op_offset, &format!("row {} is not within reach", k))))?;
+                out.copy_from_slice(source);
+                return Ok(());
+            }
+            Token::Above(count) => {
+                let source =
+                    above.previous().ok_or_else(|| located(reader.bad(op_offset, "copy from above the first row")))?;
+                if (filled as u64) + count > width {
+                    return Err(located(reader.bad(op_offset, "row is wider than the header width")));
+                }
+                let clamp = |pixel: usize| pixel.clamp(first, end) - first;
+                let range = clamp(filled) * 4..clamp(filled + count as usize) * 4;
+                out[range.clone()].copy_from_slice(&source[range]);
+                filled += count as usize;
+                let last = clamp(filled) * 4;
+                if last > 0 {
+                    last_color = Some(out[last - 4..last].try_into().unwrap());
+                }
+                continue;
+            }
+        };
+        if (filled as u64) + count > width {
+            return Err(located(reader.bad(op_offset, "row is wider than the header width")));
+        }
+        // Only the part of the run inside `out` is written.
+        let clamp = |pixel: usize| pixel.clamp(first, end) - first;
+        for pixel in out[clamp(filled) * 4..clamp(filled + count as usize) * 4].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+        filled += count as usize;
+        last_color = Some(color);
+    }
+    if (filled as u64) != width && !(partial && filled >= end) {
+        return Err(located(reader.bad(0, "row is narrower than the header width")));
+    }
+    Ok(())
+}
+
+// Expand the ops of a record whose runs span rows into `out`, which holds
+// all of its rows. `offset` is where the ops start in the file.
+pub(crate) fn decode_strip(
+    ops: &[u8],
+    offset: usize,
+    header: &BinaryHeader,
+    palette: &[[u8; 4]],
+    out: &mut [u8]
+) -> Result<(), VedError> {
+    let row_len = header.stored_width() as usize * 4;
+    let mut filled = 0;
+    let mut reader = Reader { bytes: ops, pos: 0 };
+    let located = |error: VedError| locate(error, offset, ops.len(), "record");
+
+    let mut last_color = None;
+    while !reader.is_empty() {
+        let op_offset = reader.pos;
+        let token = next_token(&mut reader, header, palette, last_color).map_err(located)?;
+        let count = match token {
+            Token::Pixels(_, count) | Token::Packed(_, count) | Token::Above(count) => count,
+            Token::Row(_) => return Err(located(reader.bad(op_offset, "row copy in a record of several rows"))),
+        };
+        let end = usize::try_from(count).ok().and_then(|count| (filled + count).checked_mul(4));
+        let end = end
+            .filter(|&end| end <= out.len())
+            .ok_or_else(|| located(reader.bad(op_offset, "record is longer than its rows")))?;
+        match token {
+            Token::Above(_) => {
+                if filled * 4 < row_len {
+                    return Err(located(reader.bad(op_offset, "copy from above the first row of the record")));
+                }
+                // The source is one row back, so a long copy reads what it
+                // has just written, a row at a time.
+                let mut start = filled * 4;
+                while start < end {
+                    let len = (end - start).min(row_len);
+                    out.copy_within(start - row_len..start - row_len + len, start);
+                    start += len;
+                }
+                last_color = Some(out[end - 4..end].try_into().unwrap());
+            }
+            Token::Pixels(color, _) => {
+                for pixel in out[filled * 4..end].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&color);
+                }
+                last_color = Some(color);
+            }
+            Token::Packed(bytes, _) => {
+                for (pixel, index) in out[filled * 4..end].chunks_exact_mut(4).zip(unpack_indices(bytes, header.index_bits)) {
+                    pixel.copy_from_slice(&palette[index]);
+                }
+                last_color = Some(out[end - 4..end].try_into().unwrap());
+            }
+            Token::Row(_) => unreachable!(),
+        }
+        filled = end / 4;
+    }
+    if filled * 4 != out.len() {
+        return Err(located(reader.bad(0, "record is shorter than its rows")));
+    }
+    Ok(())
+}
+
+// Check the checksum at the start of record `record` of a file with
+// checksums and split it off. `offset` is where the contents of the record
+// start in the file.
+pub(crate) fn check_record<'a>(
+    header: &BinaryHeader,
+    record: usize,
+    contents: &'a [u8],
+    offset: usize
+) -> Result<(usize, &'a [u8]), VedError> {
+    if !header.has_checksums() {
+        return Ok((offset, contents));
+    }
+    let corrupt =
+        || VedError::CorruptRows { offset, first: header.record_start(record), count: header.record_rows(record) as u32 };
+    let (stored, rest) = contents.split_at_checked(CHECKSUM_LEN).ok_or_else(corrupt)?;
+    if u32::from_le_bytes(stored.try_into().unwrap()) != checksum::crc32(rest) {
+        return Err(corrupt());
+    }
+    Ok((offset + CHECKSUM_LEN, rest))
+}
+
+// Split record `index`, found through the row index, into the offset and
+// bytes of its contents after any checksum, which is checked. `offset` is
+// where the record starts in the file.
+pub(crate) fn split_record<'a>(
+    header: &BinaryHeader,
+    index: usize,
+    record: &'a [u8],
+    offset: usize
+) -> Result<(usize, &'a [u8]), VedError> {
+    let mut reader = Reader { bytes: record, pos: 0 };
+    let len = reader.varint().ok().and_then(|len| usize::try_from(len).ok());
+    match len {
+        Some(len) if reader.pos + len == record.len() => {
+            check_record(header, index, &record[reader.pos..], offset + reader.pos)
+        }
+        _ => Err(VedError::BadBinary { offset, message: "row index does not match the rows".to_string() }),
+    }
+}
+
+// Split the filter off a row of a filtered file. `offset` is where the row
+// starts in the file; the ops start one byte later.
+pub(crate) fn split_filter(row: &[u8], offset: usize) -> Result<(Filter, usize, &[u8]), VedError> {
+    let bad = |message: &str| VedError::BadBinary { offset, message: message.to_string() };
+    let (&id, ops) = row.split_first().ok_or_else(|| bad("row has no filter"))?;
+    let filter = Filter::from_id(id).ok_or_else(|| bad("unknown filter"))?;
+    Ok((filter, offset + 1, ops))
+}
+
+// Split the filters of its `rows` rows off a record of a filtered file.
+pub(crate) fn split_filters(record: &[u8], offset: usize, rows: usize) -> Result<(Vec<Filter>, usize, &[u8]), VedError> {
+    let mut filters = Vec::with_capacity(rows);
+    let (mut offset, mut rest) = (offset, record);
+    for _ in 0..rows {
+        let (filter, next, ops) = split_filter(rest, offset)?;
+        filters.push(filter);
+        (offset, rest) = (next, ops);
+    }
+    Ok((filters, offset, rest))
+}
+
+// The rows a later frame changes, each as its row number and the offset
+// and ops of its record.
+pub(crate) type FrameRows<'a> = Vec<(usize, usize, &'a [u8])>;
+
+// Split the record of frame `frame`, whose contents start at `offset`, into
+// the changed rows, each with the offset and ops of its record, whose
+// checksums are checked.
+pub(crate) fn split_frame<'a>(
+    header: &BinaryHeader,
+    frame: usize,
+    contents: &'a [u8],
+    offset: usize
+) -> Result<FrameRows<'a>, VedError> {
+    let mut reader = Reader { bytes: contents, pos: 0 };
+    let located = |error: VedError| match error {
+        VedError::BadBinary { offset: pos, message } => VedError::BadBinary { offset: offset + pos, message },
+        VedError::UnexpectedEof { .. } => VedError::BadBinary {
+            offset: offset + contents.len(),
+            message: format!("frame {} ends in the middle of a row", frame),
+        },
+        error => error,
+    };
+    let mut runs = Vec::new();
+    let mut end = 0u64;
+    for _ in 0..reader.varint().map_err(located)? {
+        let run = reader.pos;
+        let (skip, count) = (reader.varint().map_err(located)?, reader.varint().map_err(located)?);
+        let start = end.checked_add(skip);
+        end = start
+            .and_then(|start| start.checked_add(count))
+            .filter(|&end| end <= header.height.into())
+            .ok_or_else(|| located(reader.bad(run, &format!("frame {} changes rows past the last one", frame))))?;
+        runs.push(start.unwrap() as usize..end as usize);
+    }
+    let mut rows = Vec::new();
+    for y in runs.into_iter().flatten() {
+        let start = reader.pos;
+        let record = skip_record(&mut reader).map_err(located)?;
+        let (ops_offset, ops) = match header.has_checksums() {
+            true => match record.split_at_checked(CHECKSUM_LEN) {
+                Some((stored, ops)) if u32::from_le_bytes(stored.try_into().unwrap()) == checksum::crc32(ops) => {
+                    (reader.pos - ops.len(), ops)
+                }
+                _ => {
+                    let message = format!("row {} of frame {} does not match its checksum", y, frame);
+                    return Err(located(reader.bad(start, &message)));
+                }
+            },
+            false => (reader.pos - record.len(), record),
+        };
+        let mut ops_reader = Reader { bytes: ops, pos: 0 };
+        if ops_reader.varint().is_ok_and(|op| op & 3 == OP_COPY && op & COPY_ROW != 0) {
+            return Err(located(reader.bad(ops_offset, "row copy in a frame after the first")));
+        }
+        rows.push((y, offset + ops_offset, ops));
+    }
+    if !reader.is_empty() {
+        return Err(located(reader.bad(reader.pos, &format!("frame {} is longer than its rows", frame))));
+    }
+    Ok(rows)
+}
+
+// Expand the changed rows of a frame after the first into `out`, and copy
+// the other rows from `reference`, which the changed rows copy from too.
+pub(crate) fn decode_frame(
+    header: &BinaryHeader,
+    palette: &[[u8; 4]],
+    rows: &[(usize, usize, &[u8])],
+    reference: &[u8],
+    out: &mut [u8]
+) -> Result<(), VedError> {
+    let row_len = header.stored_width() as usize * 4;
+    // The row of the reference is the one above that copies read.
+    let decode = |(offset, ops): (usize, &[u8]), pixels: &mut [u8], above: &[u8]| {
+        let above = Above { pixels: above, row_len, first: 0, row: 1 };
+        decode_row(ops, offset, header, palette, RowOut { first: 0, pixels, above })
+    };
+    out.copy_from_slice(reference);
+    if row_len == 0 {
+        // Zero-width rows hold no pixels but still have to be valid.
+        return rows.iter().try_for_each(|&(_, offset, ops)| decode((offset, ops), &mut [], &[]));
+    }
+    let mut changed = vec![None; header.height as usize];
+    for &(y, offset, ops) in rows {
+        changed[y] = Some((offset, ops));
+    }
+    out.par_chunks_mut(row_len)
+        .zip(reference.par_chunks(row_len))
+        .zip(changed.par_iter())
+        .filter_map(|((out, above), row)| row.map(|row| (out, above, row)))
+        .try_for_each(|(out, above, row)| decode(row, out, above))
+}
+
+// The first frame of a binary file, with the records of the frames after
+// it split up.
+struct FirstFrame<'a> {
+    // As stored, in byte planes for wider samples.
+    img: RgbaImage,
+    report: DecodeReport,
+    palette: Vec<[u8; 4]>,
+    frames: Vec<Timing>,
+    later: Vec<FrameRows<'a>>,
+}
+
+// Decode a binary file into an image with the channels it stores, once
+// its header is within the limits. In lenient mode, rows that fail their
+// checksum or do not decode are transparent black, as are the rows that
+// depend on them and those missing from a file that ends early.
+pub(crate) fn decode(bytes: &[u8], options: &DecodeOptions) -> Result<(DynamicImage, DecodeReport), VedError> {
+    let header = BinaryHeader::read(&mut (Reader { bytes, pos: MAGIC.len() }))?;
+    options.limits.check(&header.info())?;
+    let (header, data) = uncompressed(bytes, &options.limits, options.lenient)?;
+    let first = decode_first(&header, &data, options)?;
+    Ok((decode::into_image(first.img, header.layout, header.sample()), first.report))
+}
+
+// Decode every frame of a binary file, strictly, each against the
+// reference the disposal of the frames before it leaves.
+pub(crate) fn decode_frames(bytes: &[u8], limits: &DecodeLimits) -> Result<Vec<AnimationFrame>, VedError> {
+    let header = BinaryHeader::read(&mut (Reader { bytes, pos: MAGIC.len() }))?;
+    limits.check(&header.info())?;
+    let (header, data) = uncompressed(bytes, limits, false)?;
+    let FirstFrame { img, palette, frames, later, .. } =
+        decode_first(&header, &data, &DecodeOptions { lenient: false, limits: *limits })?;
+    let frame_len = img.len();
+    limits.check_memory("decoded size of all frames", (frame_len as u64).saturating_mul(frames.len() as u64))?;
+    let into_frame = |pixels: &[u8], (delay, disposal): Timing| {
+        let planes = RgbaImage::from_raw(header.stored_width(), header.height, pixels.to_vec()).unwrap();
+        AnimationFrame { image: decode::into_image(planes, header.layout, header.sample()).to_rgba8(), delay, disposal }
+    };
+    let mut out = vec![into_frame(&img, frames[0])];
+    let mut current = img.into_raw();
+    let mut reference = Cow::Owned(vec![0; frame_len]);
+    for (rows, &timing) in later.iter().zip(&frames[1..]) {
+        let mut next = vec![0; frame_len];
+        let last = out.len() - 1;
+        reference = Cow::Owned(animation::next_reference(&current, reference, frames[last].1).into_owned());
+        decode_frame(&header, &palette, rows, &reference, &mut next)?;
+        out.push(into_frame(&next, timing));
+        current = next;
+    }
+    Ok(out)
+}
+
+// Decode the first frame of a binary file, as `decode` does, and split
+// the frames after it.
+fn decode_first<'a>(header: &BinaryHeader, data: &'a [u8], options: &DecodeOptions) -> Result<FirstFrame<'a>, VedError> {
+    let header = header.clone();
+    let mut reader = Reader { bytes: data, pos: BODY_OFFSET };
+    let palette = read_palette(&mut reader, &header)?;
+    take_metadata(&mut reader, &header)?;
+    let frames = take_frames(&mut reader, &header)?;
+    let lenient = options.lenient;
+
+    // Split the records up front so they can be decoded in parallel. A
+    // lenient decode stops at the first record that does not fit in the
+    // file, and expands records it cannot split to nothing.
+    let records = header.records() as usize;
+    let mut rows = Vec::with_capacity(records.min(data.len()));
+    let mut offsets = Vec::with_capacity(rows.capacity());
+    let mut filters = Vec::new();
+    let mut damaged = vec![false; header.height as usize];
+    for record in 0..records {
+        let start = reader.pos;
+        let framed = reader.varint().and_then(|len| {
+            let len = usize::try_from(len).map_err(|_| VedError::UnexpectedEof { offset: data.len() })?;
+            Ok((reader.pos, reader.take(len)?))
+        });
+        let (offset, contents) = match framed {
+            Ok(framed) => framed,
+            Err(_) if lenient => break,
+            Err(error) => return Err(error),
+        };
+        offsets.push(start as u64);
+        let record_rows = header.record_rows(record);
+        let split = check_record(&header, record, contents, offset).and_then(|(offset, contents)| {
+            match header.is_filtered() {
+                true => split_filters(contents, offset, record_rows),
+                false => Ok((Vec::new(), offset, contents)),
+            }
+        });
+        match split {
+            Ok((record_filters, offset, ops)) => {
+                filters.extend(record_filters);
+                rows.push((offset, ops));
+            }
+            Err(error) if !lenient => return Err(error),
+            Err(_) => {
+                if header.is_filtered() {
+                    filters.extend(std::iter::repeat_n(Filter::None, record_rows));
+                }
+                let first = header.record_start(record) as usize;
+                damaged[first..first + record_rows].fill(true);
+                rows.push((offset, &[]));
+            }
+        }
+    }
+
+    let mut report = DecodeReport::default();
+    let mut trailer_repair = None;
+    let mut later = Vec::new();
+    if rows.len() < records {
+        trailer_repair = Some(Repair::MissingRows { expected: header.height, found: header.record_start(rows.len()) });
+    } else {
+        if header.has_frames() {
+            offsets.push(reader.pos as u64);
+        }
+        let trailer = split_frames(&header, &mut reader, frames.len()).and_then(|split| {
+            later = split;
+            check_trailer(&header, &mut reader, &offsets)
+        });
+        if let Err(error) = trailer {
+            if !lenient {
+                return Err(error);
+            }
+            trailer_repair = Some(Repair::IgnoredTrailer { error });
+        }
+    }
+
+    // Decode the rows straight into their slices of the image, in
+    // parallel where they do not copy from the rows above.
+    let mut img = RgbaImage::new(header.stored_width(), header.height);
+    let row_len = header.stored_width() as usize * 4;
+    if header.spans_rows() {
+        for record in decode_strips(&header, &palette, &rows, &mut img, lenient)? {
+            let first = header.record_start(record) as usize;
+            damaged[first..first + header.record_rows(record)].fill(true);
+        }
+    } else {
+        let failed = history::expand_rows(
+            &mut img,
+            row_len,
+            0,
+            0,
+            &rows,
+            |y, &(_, row)| copied_row(row, y, &header).is_some(),
+            |_, &(offset, row), RowOut { first, pixels, above }| {
+                match decode_row(row, offset, &header, &palette, RowOut { first, pixels: &mut *pixels, above }) {
+                    Ok(()) => Ok(false),
+                    Err(_) if lenient => {
+                        pixels.fill(0);
+                        Ok(true)
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+        )?;
+        for (damaged, failed) in damaged.iter_mut().zip(failed) {
+            *damaged |= failed;
+        }
+    }
+
+    // Rows that copy a damaged row or are predicted from one are damaged too.
+    for y in 0..damaged.len() {
+        let copied = rows.get(y).filter(|_| !header.spans_rows()).and_then(|&(_, row)| copied_row(row, y, &header));
+        let predicted = filters.get(y).is_some_and(|filter| !filter.is_standalone()).then(|| y.wrapping_sub(1));
+        damaged[y] |= copied.into_iter().chain(predicted).any(|k| damaged.get(k) == Some(&true));
+    }
+    if row_len > 0 {
+        // Filtered rows depend on the rows above, so they are reconstructed in order.
+        filter::unfilter_rows(&filters, &mut img, row_len, header.layout.filtered_channels());
+        for (row, _) in img.chunks_exact_mut(row_len).zip(&damaged).filter(|&(_, &damaged)| damaged) {
+            row.fill(0);
+        }
+    }
+    let mut y = 0;
+    while let Some(first) = damaged[y..].iter().position(|&damaged| damaged).map(|i| y + i) {
+        let count = damaged[first..].iter().take_while(|&&damaged| damaged).count();
+        report.repairs.push(Repair::DamagedRows { first: first as u32, count: count as u32 });
+        y = first + count;
+    }
+    report.repairs.extend(trailer_repair);
+
+    Ok(FirstFrame { img, report, palette, frames, later })
+}
+
+// Split the records of the frames after the first, which follow its
+// records where the reader is.
+fn split_frames<'a>(
+    header: &BinaryHeader,
+    reader: &mut Reader<'a>,
+    count: usize
+) -> Result<Vec<FrameRows<'a>>, VedError> {
+    (1..count)
+        .map(|frame| {
+            let len = reader.varint()?;
+            let start = reader.pos;
+            let contents = usize::try_from(len).ok().map(|len| reader.take(len));
+            let contents = contents.unwrap_or(Err(VedError::UnexpectedEof { offset: reader.bytes.len() }))?;
+            split_frame(header, frame, contents, start)
+        })
+        .collect()
+}
+
+// Check what follows the last record, where the reader is: the row index
+// against where the records start, the file hash, and that nothing else
+// follows them.
+fn check_trailer(header: &BinaryHeader, reader: &mut Reader, offsets: &[u64]) -> Result<(), VedError> {
+    if header.has_row_index() {
+        let start = reader.pos;
+        check_row_index(&reader.bytes[start..], start, offsets)?;
+        reader.take(offsets.len() * 8)?;
+    }
+    if header.has_checksums() {
+        let start = reader.pos;
+        if reader.u64()? != checksum::xxh64(&reader.bytes[..start]) {
+            return Err(VedError::CorruptFile { offset: start });
+        }
+    }
+    if !reader.is_empty() {
+        return Err(reader.bad(reader.pos, "trailing data after the last row"));
+    }
+    Ok(())
+}
+
+// Decode records whose runs span rows, in parallel, into `pixels`, which
+// holds the rows of the records one after the other. In lenient mode,
+// records that do not decode are left transparent black and returned.
+pub(crate) fn decode_strips(
+    header: &BinaryHeader,
+    palette: &[[u8; 4]],
+    records: &[(usize, &[u8])],
+    pixels: &mut [u8],
+    lenient: bool
+) -> Result<Vec<usize>, VedError> {
+    let strip_len = header.stored_width() as usize * 4 * RESTART_ROWS as usize;
+    let decode = |record: usize, out: &mut [u8], (offset, ops): (usize, &[u8])| {
+        match decode_strip(ops, offset, header, palette, out) {
+            Ok(()) => Ok(None),
+            Err(_) if lenient => {
+                out.fill(0);
+                Ok(Some(record))
+            }
+            Err(error) => Err(error),
+        }
+    };
+    if strip_len == 0 {
+        // Zero-width rows hold no pixels but still have to be valid.
+        return records.par_iter().enumerate().filter_map(|(i, &record)| decode(i, &mut [], record).transpose()).collect();
+    }
+    pixels
+        .par_chunks_mut(strip_len)
+        .zip(records.par_iter())
+        .enumerate()
+        .filter_map(|(i, (out, &record))| decode(i, out, record).transpose())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ ImageBuffer, Luma, LumaA, Rgb, Rgba };
+    use std::path::Path;
+    use super::*;
+    use crate::decode::decode_bytes;
+    use crate::encode::{ encode_image_with, ColorMode, Container, EncodeOptions };
+    use crate::filter::Filtering;
+
+    const WIDTH: u32 = 45;
+    const HEIGHT: u32 = 300;
+
+    // A value for each pixel with runs along rows, rows that repeat and
+    // rows that match the one above in places, out to past ROW_WINDOW rows.
+    fn value(x: u32, y: u32) -> u32 {
+        match y % 9 {
+            0 | 1 => y / 9 % 13,
+            2 => x / 5 + y,
+            _ => (x * 7 + y * 3) ^ (x / 8),
+        }
+    }
+
+    fn images() -> Vec<(DynamicImage, Option<ColorMode>)> {
+        let gray = |x, y| value(x, y) as u8;
+        let alpha = |x: u32, y: u32| (255 - (x + y) % 3 * 100) as u8;
+        let rgb = |x, y| [value(x, y) as u8, (value(x, y) * 3) as u8, (x ^ y) as u8];
+        // Floats with negative and NaN samples, which only survive as bits.
+        let float = |x: u32, y: u32| {
+            let v = value(x, y) as f32;
+            [v / 16.0 - 4.0, if (x + y).is_multiple_of(17) { f32::NAN } else { -v * 1e-3 }, f32::from(gray(x, y)) * 1e6]
+        };
+        let indexed = |bits: u32| {
+            move |x: u32, y: u32| {
+                let i = value(x, y) % (1 << bits);
+                Rgba([(i * 37) as u8, (i * 11) as u8, (i * 5 + 1) as u8, 255 - (i % 2 * 127) as u8])
+            }
+        };
+        vec![
+            (DynamicImage::ImageLuma8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| Luma([gray(x, y)]))), None),
+            (DynamicImage::ImageLumaA8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| LumaA([gray(x, y), alpha(x, y)]))), None),
+            (DynamicImage::ImageRgb8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| Rgb(rgb(x, y)))), None),
+            (
+                DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+                    let [r, g, b] = rgb(x, y);
+                    Rgba([r, g, b, alpha(x, y)])
+                })),
+                None,
+            ),
+            (DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, indexed(1))), Some(ColorMode::Indexed1)),
+            (DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, indexed(2))), Some(ColorMode::Indexed2)),
+            (DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, indexed(4))), Some(ColorMode::Indexed4)),
+            (DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, indexed(8))), Some(ColorMode::Indexed8)),
+            (
+                DynamicImage::ImageLuma16(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| Luma([(value(x, y) * 257) as u16]))),
+                None,
+            ),
+            (
+                DynamicImage::ImageRgba16(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+                    let [r, g, b] = rgb(x, y).map(|c| u16::from(c) * 251);
+                    Rgba([r, g, b, u16::from(alpha(x, y)) << 8])
+                })),
+                None,
+            ),
+            (DynamicImage::ImageRgb32F(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| Rgb(float(x, y)))), None),
+            (
+                DynamicImage::ImageRgba32F(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+                    let [r, g, b] = float(x, y);
+                    Rgba([r, g, b, f32::from(alpha(x, y)) / 255.0])
+                })),
+                None,
+            ),
+        ]
+    }
+
+    fn options() -> Vec<EncodeOptions> {
+        let binary = EncodeOptions::default();
+        vec![
+            EncodeOptions { container: Container::Text, ..EncodeOptions::default() },
+            binary.clone(),
+            EncodeOptions { compression: Compression::Deflate, ..binary.clone() },
+            EncodeOptions { row_index: true, ..binary.clone() },
+            EncodeOptions { filtering: Filtering::Adaptive, ..binary.clone() },
+            EncodeOptions { span_rows: true, ..binary.clone() },
+            EncodeOptions { checksums: true, ..binary.clone() },
+            EncodeOptions {
+                compression: Compression::Deflate,
+                row_index: true,
+                filtering: Filtering::Fixed(Filter::Paeth),
+                span_rows: true,
+                checksums: true,
+                ..binary
+            },
+        ]
+    }
+
+    #[test]
+    fn every_layout_round_trips() {
+        for (img, color_mode) in images() {
+            for options in options() {
+                let options = EncodeOptions { color_mode, ..options };
+                let bytes = encode_image_with(&img, &options);
+                assert_eq!(is_binary(&bytes), options.container == Container::Binary);
+                let info = decode::read_info(&bytes).unwrap();
+                assert_eq!(info.index_bits, color_mode.and_then(ColorMode::index_bits).unwrap_or(0));
+                let decoded = decode_bytes(&bytes).unwrap();
+                let label = format!("{:?} {:?}", img.color(), options);
+                match color_mode {
+                    Some(_) => assert_eq!(decoded.to_rgba8(), img.to_rgba8(), "{}", label),
+                    // Compared as bytes, since NaN is not equal to itself.
+                    None => {
+                        assert_eq!(decoded.color(), img.color(), "{}", label);
+                        assert_eq!(decoded.as_bytes(), img.as_bytes(), "{}", label);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_text_file_decodes() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let bytes = std::fs::read(root.join("output.ved")).unwrap();
+        let expected = image::open(root.join("decoded.png")).unwrap();
+        assert_eq!(decode_bytes(&bytes).unwrap().to_rgba8(), expected.to_rgba8());
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{ self, BufRead, Read, Write };
+
+// CRC-32 (IEEE) of the bytes, as stored at the start of each row record.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    crc32fast::hash(bytes)
+}
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+// XXH64 with a seed of 0, fed a piece at a time, for the hash at the end
+// of a file.
+#[derive(Clone)]
+pub(crate) struct Xxh64 {
+    acc: [u64; 4],
+    // Bytes not yet making up a whole 32-byte stripe.
+    buffer: [u8; 32],
+    buffered: usize,
+    len: u64,
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME_2)).rotate_left(31).wrapping_mul(PRIME_1)
+}
+
+fn merge(hash: u64, acc: u64) -> u64 {
+    (hash ^ round(0, acc)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl Xxh64 {
+    pub(crate) fn new() -> Xxh64 {
+        Xxh64 {
+            acc: [PRIME_1.wrapping_add(PRIME_2), PRIME_2, 0, PRIME_1.wrapping_neg()],
+            buffer: [0; 32],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (acc, lane) in self.acc.iter_mut().zip(stripe.chunks_exact(8)) {
+            *acc = round(*acc, read_u64(lane));
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        if self.buffered > 0 {
+            let take = bytes.len().min(32 - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&bytes[..take]);
+            self.buffered += take;
+            bytes = &bytes[take..];
+            if self.buffered < 32 {
+                return;
+            }
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in stripes.by_ref() {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        let [v1, v2, v3, v4] = self.acc;
+        let mut hash = if self.len >= 32 {
+            let hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            self.acc.iter().fold(hash, |hash, &acc| merge(hash, acc))
+        } else {
+            PRIME_5
+        };
+        hash = hash.wrapping_add(self.len);
+
+        let mut tail = &self.buffer[..self.buffered];
+        while tail.len() >= 8 {
+            hash ^= round(0, read_u64(tail));
+            hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+            tail = &tail[8..];
+        }
+        if tail.len() >= 4 {
+            hash ^= u64::from(u32::from_le_bytes(tail[..4].try_into().unwrap())).wrapping_mul(PRIME_1);
+            hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+            tail = &tail[4..];
+        }
+        for &byte in tail {
+            hash ^= u64::from(byte).wrapping_mul(PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+// XXH64 of the bytes in one go.
+pub(crate) fn xxh64(bytes: &[u8]) -> u64 {
+    let mut hasher = Xxh64::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+// A reader or writer hashing everything read or written through it, when
+// it has a hasher.
+pub(crate) struct Hashed<T> {
+    pub inner: T,
+    pub hasher: Option<Xxh64>,
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for Hashed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if let Some(hasher) = &mut self.hasher {
+            // The buffer is already filled, so this reads nothing.
+            if let Ok(buf) = self.inner.fill_buf() {
+                hasher.update(&buf[..amount.min(buf.len())]);
+            }
+        }
+        self.inner.consume(amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xxh64_matches_the_reference() {
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"a"), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+        // Over 32 bytes, so all four lanes are used.
+        assert_eq!(xxh64(b"Nobody inspects the spammish repetition"), 0xFBCE_A83C_8A37_8BF1);
+    }
+
+    #[test]
+    fn xxh64_does_not_depend_on_how_the_input_is_split() {
+        let bytes: Vec<u8> = (0..200u32).map(|i| (i * 31 + 7) as u8).collect();
+        let whole = xxh64(&bytes);
+        for piece in [1, 3, 8, 31, 32, 33, 100] {
+            let mut hasher = Xxh64::new();
+            for chunk in bytes.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), whole, "pieces of {}", piece);
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,248 @@
+use image::error::{ ImageFormatHint, UnsupportedError, UnsupportedErrorKind };
+use image::hooks::{ self, GenericReader };
+use image::{
+    ColorType,
+    DynamicImage,
+    ExtendedColorType,
+    GrayAlphaImage,
+    GrayImage,
+    ImageDecoder,
+    ImageEncoder,
+    ImageError,
+    ImageBuffer,
+    ImageResult,
+    RgbImage,
+    RgbaImage,
+};
+use std::io::{ BufRead, Write };
+use crate::binary::MAGIC;
+use crate::decode::{ self, DecodeOptions };
+use crate::encode::{ self, EncodeOptions };
+use crate::error::VedError;
+use crate::metadata::Metadata;
+use crate::stream::VedDecoder;
+
+/// File extension registered with the `image` crate.
+pub const EXTENSION: &str = "ved";
+
+//ANCHOR - Register
+/// Teach the `image` crate to read .ved files, so `image::open` and
+/// `image::ImageReader` handle them like any built-in format. Files are
+/// recognised by their extension, or by their first bytes when the format
+/// is guessed, and decoded within the default `DecodeLimits`. Returns false
+/// if a decoder for "ved" was already registered.
+///
+/// `image::ImageFormat` cannot be extended, so writing goes through
+/// `DynamicImage::write_with_encoder` with a `VedImageEncoder`.
+pub fn register() -> bool {
+    let registered = hooks::register_decoding_hook(
+        EXTENSION.into(),
+        Box::new(|reader: GenericReader<'_>| {
+            let decoder = VedDecoder::new(reader, &DecodeOptions::default())?;
+            Ok(Box::new(decoder) as Box<dyn ImageDecoder + '_>)
+        })
+    );
+    if registered {
+        hooks::register_format_detection_hook(EXTENSION.into(), &MAGIC, None);
+        hooks::register_format_detection_hook(EXTENSION.into(), b"ved1,", None);
+        hooks::register_format_detection_hook(EXTENSION.into(), b"ved2,", None);
+    }
+    registered
+}
+
+//ANCHOR - Decoder
+impl<R: BufRead> ImageDecoder for VedDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.info().width, self.info().height)
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.info().color_type()
+    }
+
+    fn icc_profile(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.metadata().iter().find_map(|entry| match entry {
+            Metadata::Icc(icc) => Some(icc.clone()),
+            _ => None,
+        }))
+    }
+
+    fn exif_metadata(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.metadata().iter().find_map(|entry| match entry {
+            Metadata::Exif(exif) => Some(exif.clone()),
+            _ => None,
+        }))
+    }
+
+    fn xmp_metadata(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.metadata().iter().find_map(|entry| match entry {
+            Metadata::Xmp(xmp) => Some(xmp.clone().into_bytes()),
+            _ => None,
+        }))
+    }
+
+    fn read_image(mut self, buf: &mut [u8]) -> ImageResult<()> {
+        assert_eq!(u64::try_from(buf.len()), Ok(self.total_bytes()));
+        let width = self.info().width as usize;
+        let bytes = self.info().sample.bytes();
+        let kept = decode::kept_channels(self.info().color_type());
+        if width > 0 {
+            let mut row = vec![0; width * 4 * bytes];
+            for out in buf.chunks_exact_mut(width * kept.len() * bytes) {
+                self.read_row(&mut row)?;
+                for (out, pixel) in out.chunks_exact_mut(kept.len() * bytes).zip(row.chunks_exact(4 * bytes)) {
+                    for (out, &channel) in out.chunks_exact_mut(bytes).zip(kept) {
+                        out.copy_from_slice(&pixel[channel * bytes..(channel + 1) * bytes]);
+                    }
+                }
+            }
+        }
+        self.finish()?;
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+//ANCHOR - Encoder
+/// Encodes images for `DynamicImage::write_with_encoder` and anything
+/// else that takes an `image::ImageEncoder`.
+pub struct VedImageEncoder<W: Write> {
+    writer: W,
+    options: EncodeOptions,
+}
+
+impl<W: Write> VedImageEncoder<W> {
+    /// Write the default binary container.
+    pub fn new(writer: W) -> VedImageEncoder<W> {
+        VedImageEncoder::with_options(writer, EncodeOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: EncodeOptions) -> VedImageEncoder<W> {
+        VedImageEncoder { writer, options }
+    }
+}
+
+impl<W: Write> ImageEncoder for VedImageEncoder<W> {
+    fn write_image(self, buf: &[u8], width: u32, height: u32, color_type: ExtendedColorType) -> ImageResult<()> {
+        let buf = buf.to_vec();
+        let img = match color_type {
+            ExtendedColorType::L8 => GrayImage::from_raw(width, height, buf).map(DynamicImage::ImageLuma8),
+            ExtendedColorType::La8 => GrayAlphaImage::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8),
+            ExtendedColorType::Rgb8 => RgbImage::from_raw(width, height, buf).map(DynamicImage::ImageRgb8),
+            ExtendedColorType::Rgba8 => RgbaImage::from_raw(width, height, buf).map(DynamicImage::ImageRgba8),
+            ExtendedColorType::L16 => ImageBuffer::from_raw(width, height, u16s(&buf)).map(DynamicImage::ImageLuma16),
+            ExtendedColorType::La16 => ImageBuffer::from_raw(width, height, u16s(&buf)).map(DynamicImage::ImageLumaA16),
+            ExtendedColorType::Rgb16 => ImageBuffer::from_raw(width, height, u16s(&buf)).map(DynamicImage::ImageRgb16),
+            ExtendedColorType::Rgba16 => ImageBuffer::from_raw(width, height, u16s(&buf)).map(DynamicImage::ImageRgba16),
+            ExtendedColorType::Rgb32F => ImageBuffer::from_raw(width, height, f32s(&buf)).map(DynamicImage::ImageRgb32F),
+            ExtendedColorType::Rgba32F => ImageBuffer::from_raw(width, height, f32s(&buf)).map(DynamicImage::ImageRgba32F),
+            _ => {
+                return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                    ImageFormatHint::Name(EXTENSION.to_string()),
+                    UnsupportedErrorKind::Color(color_type)
+                )));
+            }
+        };
+        let img = img.expect("buffer does not match the image dimensions");
+        encode::encode_to_writer(&img, self.writer, &self.options)?;
+        Ok(())
+    }
+
+    fn set_icc_profile(&mut self, icc_profile: Vec<u8>) -> Result<(), UnsupportedError> {
+        self.options.metadata.retain(|entry| !matches!(entry, Metadata::Icc(_)));
+        self.options.metadata.push(Metadata::Icc(icc_profile));
+        Ok(())
+    }
+
+    fn set_exif_metadata(&mut self, exif: Vec<u8>) -> Result<(), UnsupportedError> {
+        self.options.metadata.retain(|entry| !matches!(entry, Metadata::Exif(_)));
+        self.options.metadata.push(Metadata::Exif(exif));
+        Ok(())
+    }
+}
+
+// Samples of 16 bits in native byte order.
+fn u16s(buf: &[u8]) -> Vec<u16> {
+    buf.chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]])).collect()
+}
+
+// Float samples in native byte order.
+fn f32s(buf: &[u8]) -> Vec<f32> {
+    buf.chunks_exact(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+impl From<VedError> for ImageError {
+    fn from(error: VedError) -> ImageError {
+        match error {
+            VedError::Io { kind, message } => ImageError::IoError(std::io::Error::new(kind, message)),
+            error => {
+                ImageError::Decoding(image::error::DecodingError::new(ImageFormatHint::Name(EXTENSION.to_string()), error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ ImageReader, Luma, LumaA, Rgb, Rgba };
+    use std::io::{ self, Cursor };
+    use super::*;
+
+    fn images() -> Vec<DynamicImage> {
+        let (width, height) = (19, 11);
+        vec![
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, y| Luma([(x * y) as u8]))),
+            DynamicImage::ImageRgba8(ImageBuffer::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 7, 200]))),
+            DynamicImage::ImageLumaA16(ImageBuffer::from_fn(width, height, |x, y| LumaA([(x * 3001) as u16, (y * 999) as u16]))),
+            DynamicImage::ImageRgb16(ImageBuffer::from_fn(width, height, |x, y| Rgb([(x * 3001) as u16, y as u16, 65535]))),
+            DynamicImage::ImageRgb32F(ImageBuffer::from_fn(width, height, |x, y| Rgb([x as f32 / 3.0, -(y as f32), 1e9]))),
+            DynamicImage::ImageRgba32F(ImageBuffer::from_fn(width, height, |x, y| Rgba([x as f32, y as f32 * 0.1, 0.5, (x % 3) as f32 / 2.0]))),
+        ]
+    }
+
+    #[test]
+    fn images_are_written_through_the_encoder() {
+        for img in images() {
+            let mut bytes = Vec::new();
+            img.write_with_encoder(VedImageEncoder::new(&mut bytes)).unwrap();
+            let decoded = decode::decode_bytes(&bytes).unwrap();
+            assert_eq!(decoded.color(), img.color());
+            assert_eq!(decoded.as_bytes(), img.as_bytes(), "{:?}", img.color());
+        }
+    }
+
+    #[test]
+    fn registered_files_open_through_image() {
+        assert!(register());
+        assert!(!register());
+
+        let dir = std::env::temp_dir().join(format!("ved-codec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (i, img) in images().into_iter().enumerate() {
+            let bytes = encode::encode_image(&img);
+            let path = dir.join(format!("{}.{}", i, EXTENSION));
+            std::fs::write(&path, &bytes).unwrap();
+            let opened = image::open(&path).unwrap();
+            assert_eq!(opened.as_bytes(), img.as_bytes(), "{:?}", img.color());
+
+            // Without an extension, the first bytes give the format away.
+            let guessed = ImageReader::new(Cursor::new(&bytes)).with_guessed_format().unwrap();
+            assert_eq!(guessed.decode().unwrap().as_bytes(), img.as_bytes());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_map_to_image_errors() {
+        let error = ImageError::from(VedError::from(io::Error::new(io::ErrorKind::UnexpectedEof, "cut short")));
+        assert!(matches!(error, ImageError::IoError(error) if error.kind() == io::ErrorKind::UnexpectedEof));
+        let error = ImageError::from(VedError::UnexpectedEof { offset: 3 });
+        match error {
+            ImageError::Decoding(error) => assert_eq!(error.format_hint(), ImageFormatHint::Name(EXTENSION.to_string())),
+            error => panic!("{:?}", error),
+        }
+    }
+}
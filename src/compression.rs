@@ -0,0 +1,129 @@
+use flate2::Compression as Level;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{ self, BufRead, BufReader, Read, Write };
+
+/// Entropy coding applied after run-length encoding to everything that
+/// follows the header of a binary file. Text files are never compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Palette and rows are stored as they are.
+    #[default]
+    None,
+    /// Palette and rows are one raw deflate stream.
+    Deflate,
+}
+
+impl Compression {
+    /// Name used on the command line and in `ved info`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// Look up a compression by its name.
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    // Value stored in the compression bits of the header flags.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    // Wrap a writer so that everything written through it is compressed.
+    pub(crate) fn writer<W: Write>(self, writer: W) -> Sink<W> {
+        match self {
+            Compression::None => Sink::Plain(writer),
+            Compression::Deflate => Sink::Deflate(DeflateEncoder::new(writer, Level::default())),
+        }
+    }
+
+    // Wrap a reader so that everything read through it is decompressed.
+    pub(crate) fn reader<R: BufRead>(self, reader: R) -> Source<R> {
+        match self {
+            Compression::None => Source::Plain(reader),
+            Compression::Deflate => Source::Deflate(BufReader::new(DeflateDecoder::new(reader))),
+        }
+    }
+}
+
+// A writer compressing with one of the supported methods.
+pub(crate) enum Sink<W: Write> {
+    Plain(W),
+    Deflate(DeflateEncoder<W>),
+}
+
+impl<W: Write> Sink<W> {
+    // Flush any buffered compressed data and hand back the inner writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Sink::Plain(writer) => Ok(writer),
+            Sink::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(writer) => writer.write(buf),
+            Sink::Deflate(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(writer) => writer.flush(),
+            Sink::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// A reader decompressing one of the supported methods.
+pub(crate) enum Source<R: BufRead> {
+    Plain(R),
+    Deflate(BufReader<DeflateDecoder<R>>),
+}
+
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Plain(reader) => reader.read(buf),
+            Source::Deflate(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Source<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Source::Plain(reader) => reader.fill_buf(),
+            Source::Deflate(decoder) => decoder.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Source::Plain(reader) => reader.consume(amount),
+            Source::Deflate(decoder) => decoder.consume(amount),
+        }
+    }
+}
@@ -0,0 +1,870 @@
+use image::{ ColorType, DynamicImage, ImageBuffer, RgbaImage };
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::hash::BuildHasherDefault;
+use crate::binary::{ self, ChannelLayout };
+use crate::compression::Compression;
+use crate::encode::ColorHasher;
+use crate::error::VedError;
+use crate::history::{ self, RowOut };
+use crate::metadata::Metadata;
+use crate::sample::{ self, SampleType };
+
+// Line numbers of the header and palette in the text format.
+pub(crate) const HEADER_LINE: usize = 1;
+pub(crate) const PALETTE_LINE: usize = 2;
+
+/// Summary of a .ved file, read without decoding any pixels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VedInfo {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub alpha: bool,
+    /// The channels stored for each color.
+    pub layout: ChannelLayout,
+    /// Size of a palette index in bits when every pixel is one, else 0.
+    pub index_bits: u8,
+    /// Type of each channel sample.
+    pub sample: SampleType,
+    pub palette_len: usize,
+    pub rows: usize,
+    pub compression: Compression,
+    /// Whether the file carries a row index for region decoding.
+    pub row_index: bool,
+    /// Whether the rows are stored as the residuals of predictive filters.
+    pub filtered: bool,
+    /// Whether runs continue across rows, in records of RESTART_ROWS rows.
+    pub spans_rows: bool,
+    /// Whether each record of rows has a checksum and the file a hash.
+    pub checksums: bool,
+    /// Number of frames, 1 for a still image. Only `read_info` reads the
+    /// frame table; the decoders report 1 until they have.
+    pub frames: usize,
+}
+
+/// Size in bytes of a .ved file at each stage of encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeStats {
+    /// The pixels with each stored channel at its sample size.
+    pub raw: u64,
+    /// The header, before any compression.
+    pub header: u64,
+    /// The palette, before any compression.
+    pub palette: u64,
+    /// The metadata, before any compression.
+    pub metadata: u64,
+    /// The run-length encoded rows, before any compression.
+    pub rows: u64,
+    /// The row index, before any compression.
+    pub index: u64,
+    /// The checksums of the rows and the file hash, before any compression.
+    pub checksums: u64,
+    /// The frame table and the frames after the first, before any compression.
+    pub frames: u64,
+    /// The whole file as stored.
+    pub file: u64,
+}
+
+impl SizeStats {
+    /// Size of the run-length encoded file, before any compression.
+    pub fn run_length(&self) -> u64 {
+        self.header + self.palette + self.metadata + self.rows + self.index + self.checksums + self.frames
+    }
+}
+
+/// Options controlling how strictly a .ved file is decoded.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// Pad or truncate rows and fill or drop whole rows of a text file to
+    /// fit the header dimensions instead of failing. Everything repaired is
+    /// listed in the returned `DecodeReport`. Rows of a binary file that
+    /// are damaged or missing are left transparent black.
+    pub lenient: bool,
+    /// How large a file may be before it is rejected.
+    pub limits: DecodeLimits,
+}
+
+/// Limits on what a file may make the decoder allocate, checked against
+/// the header before any pixels are allocated. The defaults let through
+/// images of up to 65536 by 65536 pixels and 256 megapixels that decode
+/// into at most 1 GiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Most pixels, width times height.
+    pub max_pixels: u64,
+    pub max_palette: u64,
+    /// Longest run or copy a text token may ask for, even in lenient mode.
+    /// Binary ops can never reach past their row or record anyway.
+    pub max_run: u64,
+    /// Most bytes the decoded pixels may take, and also the decompressed
+    /// contents of a binary file or a single row record.
+    pub max_memory: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_width: 1 << 16,
+            max_height: 1 << 16,
+            max_pixels: 1 << 28,
+            max_palette: 1 << 24,
+            max_run: 1 << 28,
+            max_memory: 1 << 30,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// No limits at all, for files that are trusted.
+    pub fn none() -> DecodeLimits {
+        DecodeLimits {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_palette: u64::MAX,
+            max_run: u64::MAX,
+            max_memory: u64::MAX,
+        }
+    }
+
+    // Check the header of a file against the limits.
+    pub(crate) fn check(&self, info: &VedInfo) -> Result<(), VedError> {
+        let pixels = u64::from(info.width) * u64::from(info.height);
+        let memory = pixels.saturating_mul(4 * info.sample.bytes() as u64);
+        let checks = [
+            ("width", info.width.into(), self.max_width.into()),
+            ("height", info.height.into(), self.max_height.into()),
+            ("pixel count", pixels, self.max_pixels),
+            ("palette size", info.palette_len as u64, self.max_palette),
+            ("decoded size", memory, self.max_memory),
+        ];
+        match checks.into_iter().find(|&(_, value, limit)| value > limit) {
+            Some((what, value, limit)) => Err(VedError::LimitExceeded { what, value, limit }),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_run(&self, count: u64) -> Result<(), VedError> {
+        if count > self.max_run {
+            return Err(VedError::LimitExceeded { what: "run length", value: count, limit: self.max_run });
+        }
+        Ok(())
+    }
+
+    // Check the number of bytes some data is about to take.
+    pub(crate) fn check_memory(&self, what: &'static str, len: u64) -> Result<(), VedError> {
+        if len > self.max_memory {
+            return Err(VedError::LimitExceeded { what, value: len, limit: self.max_memory });
+        }
+        Ok(())
+    }
+}
+
+/// A mismatch with the header that lenient decoding repaired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// A short row was padded with transparent black.
+    PaddedRow { line: usize, found: u64 },
+    /// A long row was cut off at the header width.
+    TruncatedRow { line: usize, found: u64 },
+    /// The file ended early; the remaining rows are transparent black.
+    MissingRows { expected: u32, found: u32 },
+    /// Rows past the header height, starting at `line`, were ignored.
+    DroppedRows { line: usize, count: usize },
+    /// Rows of a binary file that fail their checksum or do not decode,
+    /// or are predicted from or copy such rows, are transparent black.
+    DamagedRows { first: u32, count: u32 },
+    /// What follows the last row of a binary file, its row index and
+    /// hash, was ignored because of this error.
+    IgnoredTrailer { error: VedError },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::PaddedRow { line, found } => {
+                write!(f, "{}: padded row of {} pixels with transparent black", line, found)
+            }
+            Repair::TruncatedRow { line, found } => {
+                write!(f, "{}: truncated row of {} pixels", line, found)
+            }
+            Repair::MissingRows { expected, found } => {
+                write!(f, "filled {} missing rows with transparent black", expected - found)
+            }
+            Repair::DroppedRows { line, count } => {
+                write!(f, "{}: dropped {} rows past the header height", line, count)
+            }
+            Repair::DamagedRows { first, count: 1 } => {
+                write!(f, "filled damaged row {} with transparent black", first)
+            }
+            Repair::DamagedRows { first, count } => {
+                write!(f, "filled damaged rows {} to {} with transparent black", first, first + count - 1)
+            }
+            Repair::IgnoredTrailer { error } => write!(f, "ignored the end of the file: {}", error),
+        }
+    }
+}
+
+/// Everything lenient decoding changed to make the file fit its header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeReport {
+    pub repairs: Vec<Repair>,
+}
+
+// Palette of a text file by index. Indices need not be contiguous.
+pub(crate) type Palette = HashMap<usize, [u8; 4], BuildHasherDefault<ColorHasher>>;
+
+// Fields of the first line of a .ved file.
+pub(crate) struct Header {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub layout: ChannelLayout,
+    // Size of a palette index in bits when every pixel is one, else 0.
+    pub index_bits: u8,
+    pub sample: SampleType,
+}
+
+impl VedInfo {
+    /// The image type the file decodes to: its channels and sample type,
+    /// except that float gray decodes to rgb.
+    pub fn color_type(&self) -> ColorType {
+        color_type(self.layout, self.sample)
+    }
+}
+
+impl Header {
+    // Number of pixels stored for each row, one per byte plane of every pixel.
+    pub(crate) fn stored_width(&self) -> u32 {
+        self.width * self.sample.bytes() as u32
+    }
+
+    // Summary of a text file with this header and a palette of
+    // `palette_len` colors.
+    pub(crate) fn info(&self, palette_len: usize) -> VedInfo {
+        VedInfo {
+            version: self.version,
+            width: self.width,
+            height: self.height,
+            alpha: self.layout.has_alpha(),
+            layout: self.layout,
+            index_bits: self.index_bits,
+            sample: self.sample,
+            palette_len,
+            rows: self.height as usize,
+            compression: Compression::None,
+            row_index: false,
+            filtered: false,
+            spans_rows: false,
+            checksums: false,
+            frames: 1,
+        }
+    }
+}
+
+// View the file as text, pointing at the first byte that is not UTF-8.
+fn as_text(bytes: &[u8]) -> Result<&str, VedError> {
+    std::str::from_utf8(bytes).map_err(|error| {
+        let valid = &bytes[..error.valid_up_to()];
+        let line = valid.iter().filter(|&&b| b == b'\n').count() + 1;
+        let line_start = valid.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        VedError::InvalidUtf8 { line, column: valid.len() - line_start + 1 }
+    })
+}
+
+// Parse the header line: "ved2,width,height,layout" with an optional
+// ",pN" for indexed files and ",u16" or ",f32" for wider samples, or the
+// unversioned "width,height" (optionally followed by ",rgba") of legacy
+// files.
+pub(crate) fn parse_header(line: Option<&str>) -> Result<Header, VedError> {
+    let bad_header = |message: String| VedError::BadHeader { line: HEADER_LINE, message };
+    let line = line.ok_or_else(|| bad_header("file is empty".to_string()))?;
+
+    let mut dims: Vec<&str> = line.split(',').collect();
+    let version = match dims[0].strip_prefix("ved") {
+        Some(version) => {
+            dims.remove(0);
+            version.parse::<u32>().map_err(|_| bad_header(format!("invalid version '{}'", version)))?
+        }
+        None => 0,
+    };
+    if version > crate::TEXT_VERSION {
+        return Err(VedError::UnsupportedVersion(version));
+    }
+    let layout = match dims.get(2) {
+        None if version == 0 => ChannelLayout::Rgb,
+        Some(name) => ChannelLayout::from_name(name)
+            .ok_or_else(|| bad_header(format!("unknown channel layout '{}'", name)))?,
+        None => return Err(bad_header("missing channel layout".to_string())),
+    };
+    let (mut index_bits, mut sample) = (0, SampleType::U8);
+    for field in dims.iter().skip(3) {
+        match SampleType::from_name(field) {
+            Some(field_sample) => sample = field_sample,
+            None => {
+                index_bits = field
+                    .strip_prefix('p')
+                    .and_then(|bits| bits.parse().ok())
+                    .filter(|&bits| binary::is_index_size(bits))
+                    .ok_or_else(|| bad_header(format!("invalid index size '{}'", field)))?;
+            }
+        }
+    }
+    if dims.len() > 5 || dims.len() < 2 {
+        return Err(bad_header(format!("expected width,height but found '{}'", line)));
+    }
+    let parse_dim = |dim: &str| dim.parse::<u32>().map_err(|_| bad_header(format!("invalid dimension '{}'", dim)));
+    let width = parse_dim(dims[0])?;
+    if width.checked_mul(sample.bytes() as u32).is_none() {
+        return Err(bad_header(format!("width {} is too wide for {} samples", width, sample.name())));
+    }
+    Ok(Header { version, width, height: parse_dim(dims[1])?, layout, index_bits, sample })
+}
+
+// Parse the stored channels of a color in hex: "RRGGBB" in an rgb file,
+// "RRGGBBAA" in an rgba file, "LL" in an l file and "LLAA" in an la file.
+fn parse_color(hex: &str, layout: ChannelLayout) -> Option<[u8; 4]> {
+    if hex.len() != 2 * layout.channels() {
+        return None;
+    }
+    let nibble = |digit: u8| (digit as char).to_digit(16).map(|value| value as u8);
+    let mut stored = [0; 4];
+    for (channel, pair) in stored.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *channel = (nibble(pair[0])? << 4) | nibble(pair[1])?;
+    }
+    Some(layout.load(&stored))
+}
+
+// Parse a palette index: base 64 from version 2 on, in the digits of
+// `encode::INDEX_DIGITS`, and decimal before.
+fn parse_index(token: &str, version: u32) -> Option<usize> {
+    if version < 2 {
+        return token.parse().ok();
+    }
+    if token.is_empty() {
+        return None;
+    }
+    token.bytes().try_fold(0usize, |index, digit| {
+        let value = match digit {
+            b'0'..=b'9' => digit - b'0',
+            b'A'..=b'Z' => digit - b'A' + 10,
+            b'a'..=b'z' => digit - b'a' + 36,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        index.checked_mul(64)?.checked_add(value.into())
+    })
+}
+
+// Parse the palette line: comma-separated "index=color" entries. Indices of
+// an indexed file have to fit its index size.
+pub(crate) fn parse_palette(line: Option<&str>, header: &Header) -> Result<Palette, VedError> {
+    let line = line.ok_or(VedError::MissingPalette { line: PALETTE_LINE })?;
+    let mut variables = Palette::default();
+    if line.is_empty() {
+        return Ok(variables);
+    }
+
+    let mut column = 1;
+    for var in line.split(',') {
+        let entry = var
+            .split_once('=')
+            .and_then(|(index, color)| Some((parse_index(index, header.version)?, parse_color(color, header.layout)?)))
+            .filter(|&(index, _)| header.index_bits == 0 || index < 1 << header.index_bits);
+        let (index, color) = entry.ok_or_else(|| VedError::BadPaletteEntry {
+            line: PALETTE_LINE,
+            column,
+            entry: var.to_string(),
+        })?;
+        variables.insert(index, color);
+        column += var.len() + 1;
+    }
+    Ok(variables)
+}
+
+// The earliest row that row `y` copies from, if it copies at all.
+pub(crate) fn copied_row(row: &str, y: usize) -> Option<usize> {
+    match row.strip_prefix('=') {
+        Some(k) => k.parse().ok(),
+        None if row.contains('^') => y.checked_sub(1),
+        None => None,
+    }
+}
+
+// Expand one row of tokens into `out`. In lenient mode a row of the wrong
+// width is padded with transparent black or truncated, and the repair is
+// returned. When `out` does not cover the whole row, expanding stops as
+// soon as it is filled and the rest of the row is not checked; rows that
+// copy from the row above have to be expanded from column 0.
+pub(crate) fn decode_row(
+    row: &str,
+    line: usize,
+    header: &Header,
+    variables: &Palette,
+    options: &DecodeOptions,
+    out: RowOut
+) -> Result<Option<Repair>, VedError> {
+    let lenient = options.lenient;
+    let RowOut { first, pixels: out, above } = out;
+    if let Some(k) = row.strip_prefix('=') {
+        let source = k.parse().ok().and_then(|k| above.get(k));
+        let source = source.ok_or_else(|| VedError::BadToken { line, column: 1, token: row.to_string() })?;
+        out.copy_from_slice(source);
+        return Ok(None);
+    }
+    let width = header.stored_width() as usize;
+    let end = first + out.len() / 4;
+    let partial = first > 0 || end < width;
+    // Number of pixels the row expands to, including any past the width.
+    let mut found: u64 = 0;
+
+    let mut last_color = None;
+    let mut column = 1;
+    // An empty line is a row of zero pixels, not a single empty token.
+    for token in row.split(',').filter(|_| !row.is_empty()) {
+        if partial && found >= end as u64 {
+            return Ok(None);
+        }
+        let bad_token = || VedError::BadToken { line, column, token: token.to_string() };
+        let nothing_to_repeat = || VedError::NothingToRepeat { line, column };
+
+        // Only the part of the run inside `out` is written.
+        let clamp = |pixel: u64| (pixel.min(end as u64) as usize).max(first) - first;
+        if let Some(count) = token.strip_prefix('^') {
+            let count = count.parse::<u64>().ok().filter(|&count| count > 0).ok_or_else(bad_token)?;
+            options.limits.check_run(count)?;
+            let source = above.previous().ok_or_else(bad_token)?;
+            let start = clamp(found);
+            found = found.saturating_add(count);
+            if found > (width as u64) && !lenient {
+                return Err(VedError::RowWidthMismatch { line, expected: header.stored_width(), found });
+            }
+            let range = start * 4..clamp(found) * 4;
+            out[range.clone()].copy_from_slice(&source[range]);
+            let last = clamp(found) * 4;
+            last_color = (last > 0).then(|| out[last - 4..last].try_into().unwrap()).or(last_color);
+            column += token.len() + 1;
+            continue;
+        }
+
+        let run = if header.version < 2 { token.strip_prefix('x') } else { token.strip_prefix('*') };
+        let (color, count) = if let Some(count) = run {
+            let count = count.parse::<u64>().map_err(|_| bad_token())?;
+            options.limits.check_run(count)?;
+            (last_color.ok_or_else(nothing_to_repeat)?, count)
+        } else if token.is_empty() {
+            (last_color.ok_or_else(nothing_to_repeat)?, 1)
+        } else if header.version == 0 {
+            // Legacy files: anything that parses as a known index is one.
+            let variable = token.parse::<usize>().ok().and_then(|index| variables.get(&index));
+            let color = match variable {
+                Some(color) => *color,
+                None => {
+                    let hex = token.strip_prefix('#').unwrap_or(token);
+                    parse_color(hex, header.layout).ok_or_else(bad_token)?
+                }
+            };
+            (color, 1)
+        } else if let Some(hex) = token.strip_prefix('#') {
+            // Every pixel of an indexed file is in the palette.
+            let color = Some(hex).filter(|_| header.index_bits == 0).and_then(|hex| parse_color(hex, header.layout));
+            (color.ok_or_else(bad_token)?, 1)
+        } else {
+            let index = parse_index(token, header.version).ok_or_else(bad_token)?;
+            (*variables.get(&index).ok_or(VedError::UnknownIndex { line, column, index })?, 1)
+        };
+
+        let start = clamp(found);
+        found = found.saturating_add(count);
+        if found > (width as u64) && !lenient {
+            return Err(VedError::RowWidthMismatch { line, expected: header.stored_width(), found });
+        }
+        for pixel in out[start * 4..clamp(found) * 4].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+        last_color = Some(color);
+        column += token.len() + 1;
+    }
+
+    if found > (width as u64) {
+        Ok(Some(Repair::TruncatedRow { line, found }))
+    } else if partial && found >= end as u64 {
+        Ok(None)
+    } else if found < (width as u64) {
+        if !lenient {
+            return Err(VedError::RowWidthMismatch { line, expected: header.stored_width(), found });
+        }
+        out[(found as usize).max(first) * 4 - first * 4..].fill(0);
+        Ok(Some(Repair::PaddedRow { line, found }))
+    } else {
+        Ok(None)
+    }
+}
+
+//ANCHOR - Info
+/// Read the dimensions and palette size of a .ved file.
+pub fn read_info(bytes: &[u8]) -> Result<VedInfo, VedError> {
+    if binary::is_binary(bytes) {
+        return binary::read_info(bytes);
+    }
+    let mut lines = as_text(bytes)?.lines();
+    let header = parse_header(lines.next())?;
+    let variables = parse_palette(lines.next(), &header)?;
+
+    Ok(VedInfo { rows: lines.count(), ..header.info(variables.len()) })
+}
+
+/// Read the text, EXIF, XMP and ICC profiles stored with an image; text
+/// files and binary files before version 3 have none.
+pub fn read_metadata(bytes: &[u8]) -> Result<Vec<Metadata>, VedError> {
+    if binary::is_binary(bytes) {
+        return binary::read_metadata(bytes);
+    }
+    parse_header(as_text(bytes)?.lines().next())?;
+    Ok(Vec::new())
+}
+
+/// Measure how much each stage of a .ved file takes.
+pub fn read_stats(bytes: &[u8]) -> Result<SizeStats, VedError> {
+    if binary::is_binary(bytes) {
+        return binary::read_stats(bytes);
+    }
+    let mut lines = as_text(bytes)?.split_inclusive('\n');
+    let header_line = lines.next();
+    let header = parse_header(header_line.map(|line| line.trim_end_matches(['\r', '\n'])))?;
+    let palette = lines.next().map_or(0, str::len);
+
+    Ok(SizeStats {
+        raw: raw_len(header.stored_width(), header.height, header.layout, header.index_bits),
+        header: header_line.map_or(0, str::len) as u64,
+        palette: palette as u64,
+        metadata: 0,
+        rows: lines.map(str::len).sum::<usize>() as u64,
+        index: 0,
+        checksums: 0,
+        frames: 0,
+        file: bytes.len() as u64,
+    })
+}
+
+// Size of the pixels with one byte per stored channel, or with each row of
+// indices packed into whole bytes in an indexed file, where `width` counts
+// the stored pixels of a row.
+pub(crate) fn raw_len(width: u32, height: u32, layout: ChannelLayout, index_bits: u8) -> u64 {
+    let row = match index_bits {
+        0 => (width as u64) * (layout.channels() as u64),
+        bits => binary::packed_len(width as usize, bits) as u64,
+    };
+    row * (height as u64)
+}
+
+//ANCHOR - Decode
+// The image type a file decodes to: the channels it stores, with its
+// sample type. Float gray decodes to rgb, which `image` has a type for.
+pub(crate) fn color_type(layout: ChannelLayout, sample: SampleType) -> ColorType {
+    match (sample, layout) {
+        (SampleType::U8, ChannelLayout::L) => ColorType::L8,
+        (SampleType::U8, ChannelLayout::La) => ColorType::La8,
+        (SampleType::U8, ChannelLayout::Rgb) => ColorType::Rgb8,
+        (SampleType::U8, ChannelLayout::Rgba) => ColorType::Rgba8,
+        (SampleType::U16, ChannelLayout::L) => ColorType::L16,
+        (SampleType::U16, ChannelLayout::La) => ColorType::La16,
+        (SampleType::U16, ChannelLayout::Rgb) => ColorType::Rgb16,
+        (SampleType::U16, ChannelLayout::Rgba) => ColorType::Rgba16,
+        (SampleType::F32, ChannelLayout::L | ChannelLayout::Rgb) => ColorType::Rgb32F,
+        (SampleType::F32, ChannelLayout::La | ChannelLayout::Rgba) => ColorType::Rgba32F,
+    }
+}
+
+// Which channels of an RGBA pixel an image of this color type keeps.
+pub(crate) fn kept_channels(color: ColorType) -> &'static [usize] {
+    match color.channel_count() {
+        1 => &[0],
+        2 => &[0, 3],
+        3 => &[0, 1, 2],
+        _ => &[0, 1, 2, 3],
+    }
+}
+
+// The `channels` of every RGBA pixel.
+fn keep<T: Copy + Send + Sync>(samples: &[T], channels: &[usize]) -> Vec<T> {
+    samples.par_chunks_exact(4).flat_map_iter(|pixel| channels.iter().map(|&c| pixel[c])).collect()
+}
+
+// Turn decoded RGBA pixels, byte planes for wider samples, into the image
+// type of the file, see `color_type`.
+pub(crate) fn into_image(planes: RgbaImage, layout: ChannelLayout, sample: SampleType) -> DynamicImage {
+    let (width, height) = (planes.width() / sample.bytes() as u32, planes.height());
+    let color = color_type(layout, sample);
+    let channels = kept_channels(color);
+    let mut raw = planes.into_raw();
+    if sample != SampleType::U8 {
+        let mut merged = vec![0; raw.len()];
+        sample::merge_planes(&raw, width as usize, sample, layout.has_alpha(), &mut merged);
+        raw = merged;
+    }
+    let u16s = |raw: &[u8]| -> Vec<u16> { raw.par_chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]])).collect() };
+    let f32s = |raw: &[u8]| -> Vec<f32> {
+        raw.par_chunks_exact(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect()
+    };
+    let img = match color {
+        ColorType::L8 => ImageBuffer::from_raw(width, height, keep(&raw, channels)).map(DynamicImage::ImageLuma8),
+        ColorType::La8 => ImageBuffer::from_raw(width, height, keep(&raw, channels)).map(DynamicImage::ImageLumaA8),
+        ColorType::Rgb8 => ImageBuffer::from_raw(width, height, keep(&raw, channels)).map(DynamicImage::ImageRgb8),
+        ColorType::L16 => ImageBuffer::from_raw(width, height, keep(&u16s(&raw), channels)).map(DynamicImage::ImageLuma16),
+        ColorType::La16 => ImageBuffer::from_raw(width, height, keep(&u16s(&raw), channels)).map(DynamicImage::ImageLumaA16),
+        ColorType::Rgb16 => ImageBuffer::from_raw(width, height, keep(&u16s(&raw), channels)).map(DynamicImage::ImageRgb16),
+        ColorType::Rgba16 => ImageBuffer::from_raw(width, height, u16s(&raw)).map(DynamicImage::ImageRgba16),
+        ColorType::Rgb32F => ImageBuffer::from_raw(width, height, keep(&f32s(&raw), channels)).map(DynamicImage::ImageRgb32F),
+        ColorType::Rgba32F => ImageBuffer::from_raw(width, height, f32s(&raw)).map(DynamicImage::ImageRgba32F),
+        _ => ImageBuffer::from_raw(width, height, raw).map(DynamicImage::ImageRgba8),
+    };
+    img.expect("pixels match the image dimensions")
+}
+
+/// Decode the bytes of a .ved file into an image, rejecting any file whose
+/// rows do not match the header dimensions. The image has the channels the
+/// file stores.
+pub fn decode_bytes(bytes: &[u8]) -> Result<DynamicImage, VedError> {
+    let (img, _) = decode_bytes_with(bytes, &DecodeOptions::default())?;
+    Ok(img)
+}
+
+/// Decode the bytes of a .ved file into an image, with a report of what
+/// lenient decoding had to repair.
+pub fn decode_bytes_with(
+    bytes: &[u8],
+    options: &DecodeOptions
+) -> Result<(DynamicImage, DecodeReport), VedError> {
+    if binary::is_binary(bytes) {
+        return binary::decode(bytes, options);
+    }
+    let mut lines = as_text(bytes)?.lines();
+    let header = parse_header(lines.next())?;
+    let variables = parse_palette(lines.next(), &header)?;
+    options.limits.check(&header.info(variables.len()))?;
+    let mut report = DecodeReport::default();
+
+    // Collect all remaining lines into a vector.
+    let mut rows: Vec<&str> = lines.collect();
+    let first_extra_line = PALETTE_LINE + header.height as usize + 1;
+    let mut rows_repair = None;
+    if rows.len() > (header.height as usize) {
+        if !options.lenient {
+            return Err(VedError::TooManyRows { line: first_extra_line, expected: header.height });
+        }
+        let count = rows.len() - header.height as usize;
+        rows.truncate(header.height as usize);
+        rows_repair = Some(Repair::DroppedRows { line: first_extra_line, count });
+    } else if rows.len() < (header.height as usize) {
+        let found = rows.len() as u32;
+        if !options.lenient {
+            return Err(VedError::TooFewRows {
+                line: PALETTE_LINE + rows.len() + 1,
+                expected: header.height,
+                found,
+            });
+        }
+        rows_repair = Some(Repair::MissingRows { expected: header.height, found });
+    }
+
+    // Decode the rows straight into their slices of the image, in
+    // parallel where they do not copy from the rows above; missing rows
+    // stay transparent black.
+    let mut img = RgbaImage::new(header.stored_width(), header.height);
+    let row_repairs = history::expand_rows(
+        &mut img,
+        header.stored_width() as usize * 4,
+        0,
+        0,
+        &rows,
+        |y, row| copied_row(row, y).is_some(),
+        |y, row, out| decode_row(row, PALETTE_LINE + y + 1, &header, &variables, options, out)
+    )?;
+    report.repairs.extend(row_repairs.into_iter().flatten());
+    report.repairs.extend(rows_repair);
+
+    Ok((into_image(img, header.layout, header.sample), report))
+}
+
+//ANCHOR - Verify
+/// What `verify` found wrong with a .ved file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Whether the file stores row checksums and a file hash; without them
+    /// only damage that stops rows decoding can be found.
+    pub checksums: bool,
+    /// The rows that are damaged or missing, as ranges in order.
+    pub rows: Vec<Range<u32>>,
+    /// Every repair lenient decoding made, including ones to the row index
+    /// and file hash that no row is blamed for.
+    pub repairs: Vec<Repair>,
+}
+
+impl VerifyReport {
+    /// Whether nothing is wrong with the file.
+    pub fn is_ok(&self) -> bool {
+        self.repairs.is_empty()
+    }
+}
+
+/// Check a .ved file for damage by decoding it leniently, reporting exactly
+/// which rows fail their checksums, do not decode or are missing. Only a
+/// header or palette too damaged to read is an error.
+pub fn verify(bytes: &[u8]) -> Result<VerifyReport, VedError> {
+    let info = read_info(bytes)?;
+    let (_, report) = decode_bytes_with(bytes, &DecodeOptions { lenient: true, ..DecodeOptions::default() })?;
+    let mut rows: Vec<Range<u32>> = report
+        .repairs
+        .iter()
+        .filter_map(|repair| match *repair {
+            Repair::PaddedRow { line, .. } | Repair::TruncatedRow { line, .. } => {
+                let row = (line - PALETTE_LINE - 1) as u32;
+                Some(row..row + 1)
+            }
+            Repair::MissingRows { expected, found } => Some(found..expected),
+            Repair::DamagedRows { first, count } => Some(first..first + count),
+            Repair::DroppedRows { .. } | Repair::IgnoredTrailer { .. } => None,
+        })
+        .collect();
+    rows.sort_by_key(|range| range.start);
+    rows.dedup_by(|next, range| {
+        let touches = next.start <= range.end;
+        if touches {
+            range.end = range.end.max(next.end);
+        }
+        touches
+    });
+    Ok(VerifyReport { checksums: info.checksums, rows, repairs: report.repairs })
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::DeflateEncoder;
+    use std::io::{ BufReader, Cursor, Write };
+    use super::*;
+    use crate::encode::{ encode_image_with, EncodeOptions };
+    use crate::region::RegionDecoder;
+    use crate::stream::VedDecoder;
+
+    // The magic bytes and header of a version 4 rgb file.
+    fn binary_header(width: u32, height: u32, flags: u8, palette_len: u32) -> Vec<u8> {
+        let mut bytes = binary::MAGIC.to_vec();
+        bytes.push(4);
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&[3, flags]);
+        bytes.extend_from_slice(&palette_len.to_le_bytes());
+        bytes
+    }
+
+    // Check that every decoder rejects the file for exceeding `what`.
+    fn assert_rejected(bytes: &[u8], limits: DecodeLimits, what: &str) {
+        let is_limit = |error: VedError| matches!(error, VedError::LimitExceeded { what: found, .. } if found == what);
+        for lenient in [false, true] {
+            let options = DecodeOptions { lenient, limits };
+            let error = decode_bytes_with(bytes, &options).unwrap_err();
+            assert!(is_limit(error), "decode_bytes_with, lenient: {}", lenient);
+            let error = VedDecoder::new(BufReader::new(bytes), &options)
+                .and_then(|mut decoder| {
+                    let mut row = vec![0; decoder.info().width as usize * 4];
+                    while decoder.read_row(&mut row)? {}
+                    decoder.finish()
+                })
+                .unwrap_err();
+            assert!(is_limit(error), "VedDecoder, lenient: {}", lenient);
+        }
+        let error = RegionDecoder::with_limits(Cursor::new(bytes), limits)
+            .and_then(|mut decoder| {
+                let (width, height) = (decoder.info().width, decoder.info().height);
+                decoder.decode_region(0, 0, width, height)
+            })
+            .unwrap_err();
+        assert!(is_limit(error), "RegionDecoder");
+    }
+
+    #[test]
+    fn huge_dimensions_are_rejected() {
+        assert_rejected(b"ved2,65536,65536,rgb\n\n", DecodeLimits::default(), "pixel count");
+        assert_rejected(&binary_header(65536, 65536, 0, 0), DecodeLimits::default(), "pixel count");
+        assert_rejected(b"ved2,65537,1,rgb\n\n", DecodeLimits::default(), "width");
+    }
+
+    #[test]
+    fn long_runs_are_rejected() {
+        let bytes = format!("ved2,4,1,rgb\n0=FF0000\n0,*{}\n", (1u64 << 28) + 1);
+        assert_rejected(bytes.as_bytes(), DecodeLimits::default(), "run length");
+        let limits = DecodeLimits { max_run: 2, ..DecodeLimits::default() };
+        assert_rejected(b"ved2,4,1,rgb\n0=FF0000\n0,*3\n", limits, "run length");
+    }
+
+    #[test]
+    fn large_palettes_are_rejected() {
+        assert_rejected(&binary_header(1, 1, 0, (1 << 24) + 1), DecodeLimits::default(), "palette size");
+        let limits = DecodeLimits { max_palette: 2, ..DecodeLimits::default() };
+        assert_rejected(b"ved2,1,1,rgb\n0=FF0000,1=00FF00,2=0000FF\n0\n", limits, "palette size");
+    }
+
+    #[test]
+    fn deflate_bombs_are_rejected() {
+        let mut bytes = binary_header(1, 1, 1, 0);
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&vec![0; 4 << 20]).unwrap();
+        bytes.extend_from_slice(&encoder.finish().unwrap());
+        assert!(bytes.len() < 8 << 10);
+        let limits = DecodeLimits { max_memory: 1 << 20, ..DecodeLimits::default() };
+        let options = DecodeOptions { lenient: false, limits };
+        assert!(matches!(
+            decode_bytes_with(&bytes, &options),
+            Err(VedError::LimitExceeded { what: "decompressed size", .. })
+        ));
+        assert!(matches!(
+            RegionDecoder::with_limits(Cursor::new(&bytes), limits).map(|_| ()),
+            Err(VedError::LimitExceeded { what: "decompressed size", .. })
+        ));
+    }
+
+    #[test]
+    fn a_flipped_bit_damages_only_its_record() {
+        let (width, height) = (40, 30);
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 5 + y) as u8, (x ^ y) as u8, (y * 9) as u8])
+        }));
+        let options = EncodeOptions { checksums: true, row_index: true, ..EncodeOptions::default() };
+        let bytes = encode_image_with(&img, &options);
+        assert!(verify(&bytes).unwrap().repairs.is_empty());
+
+        // The row index, with where the frame ends, sits before the file hash.
+        let index_start = bytes.len() - 8 - (height as usize + 1) * 8;
+        let offset = |row: usize| {
+            let entry = index_start + row * 8;
+            u64::from_le_bytes(bytes[entry..entry + 8].try_into().unwrap()) as usize
+        };
+        for row in [0, 17, height - 1] {
+            let mut damaged = bytes.clone();
+            damaged[offset(row as usize + 1) - 1] ^= 0x10;
+
+            let report = verify(&damaged).unwrap();
+            assert_eq!(report.rows, vec![row..row + 1]);
+            let blamed: Vec<&Repair> =
+                report.repairs.iter().filter(|repair| matches!(repair, Repair::DamagedRows { .. })).collect();
+            assert_eq!(blamed, vec![&Repair::DamagedRows { first: row, count: 1 }]);
+
+            let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+            let (decoded, report) = decode_bytes_with(&damaged, &options).unwrap();
+            assert!(report.repairs.contains(&Repair::DamagedRows { first: row, count: 1 }));
+            let (decoded, original) = (decoded.to_rgba8(), img.to_rgba8());
+            for y in 0..height {
+                let same = (0..width).all(|x| decoded.get_pixel(x, y) == original.get_pixel(x, y));
+                assert_eq!(same, y != row, "row {} with row {} damaged", y, row);
+            }
+            assert!(decode_bytes(&damaged).is_err());
+        }
+    }
+}
@@ -0,0 +1,788 @@
+use image::{ DynamicImage, ImageBuffer, Rgba, RgbaImage };
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::{ BuildHasherDefault, Hasher };
+use std::io::{ self, Write };
+use rayon::prelude::*;
+use crate::binary::{ self, ChannelLayout };
+use crate::compression::Compression;
+use crate::filter::{ self, Filtering };
+use crate::metadata::Metadata;
+use crate::quantize::{ self, Quantize };
+use crate::sample::{ self, SampleType };
+use crate::stream::VedEncoder;
+
+/// Which of the two .ved layouts to write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Container {
+    /// The binary container described in `binary`.
+    #[default]
+    Binary,
+    /// The line-based text format described on `write_text_header`.
+    Text,
+}
+
+/// How the colors of a file are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// One gray channel.
+    L,
+    /// A gray channel and alpha.
+    La,
+    Rgb,
+    Rgba,
+    /// Every pixel is a palette index of 1, 2, 4 or 8 bits, and the palette
+    /// holds every color of the image.
+    Indexed1,
+    Indexed2,
+    Indexed4,
+    Indexed8,
+}
+
+impl ColorMode {
+    /// Look up a color mode by its command-line name: "l", "la", "rgb",
+    /// "rgba", or "p1", "p2", "p4" or "p8" for the indexed modes.
+    pub fn from_name(name: &str) -> Option<ColorMode> {
+        match name {
+            "l" => Some(ColorMode::L),
+            "la" => Some(ColorMode::La),
+            "rgb" => Some(ColorMode::Rgb),
+            "rgba" => Some(ColorMode::Rgba),
+            "p1" => Some(ColorMode::Indexed1),
+            "p2" => Some(ColorMode::Indexed2),
+            "p4" => Some(ColorMode::Indexed4),
+            "p8" => Some(ColorMode::Indexed8),
+            _ => None,
+        }
+    }
+
+    /// Size of a palette index in bits, for the indexed modes.
+    pub fn index_bits(self) -> Option<u8> {
+        match self {
+            ColorMode::Indexed1 => Some(1),
+            ColorMode::Indexed2 => Some(2),
+            ColorMode::Indexed4 => Some(4),
+            ColorMode::Indexed8 => Some(8),
+            _ => None,
+        }
+    }
+
+    // The channels stored for each color. Palette colors of the indexed
+    // modes are stored gray or not and with alpha or not as they need.
+    pub(crate) fn layout(self, gray: bool, alpha: bool) -> ChannelLayout {
+        match self {
+            ColorMode::L => ChannelLayout::L,
+            ColorMode::La => ChannelLayout::La,
+            ColorMode::Rgb => ChannelLayout::Rgb,
+            ColorMode::Rgba => ChannelLayout::Rgba,
+            _ => ChannelLayout::of(gray, alpha),
+        }
+    }
+}
+
+/// Options controlling how an image is encoded.
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    pub container: Container,
+    /// Entropy coding after run-length encoding; binary container only.
+    pub compression: Compression,
+    /// Append the offset of every row so regions can be decoded without
+    /// reading the rows before them; binary container only.
+    pub row_index: bool,
+    /// Predict each row from its neighbours before run-length encoding;
+    /// binary container only.
+    pub filtering: Filtering,
+    /// Keep at most this many of the most frequent colors in the palette;
+    /// the rest are stored as literals.
+    pub max_palette: Option<usize>,
+    /// Reduce the image to fewer colors first. This is lossy.
+    pub quantize: Option<Quantize>,
+    /// Let runs continue from one row into the next, restarting every
+    /// `binary::RESTART_ROWS` rows; binary container only.
+    pub span_rows: bool,
+    /// How to store the colors. When None, it is picked from the pixels:
+    /// indices of up to 4 bits for up to 16 colors, a gray channel when
+    /// every pixel is gray, 8-bit indices for up to 256 colors and rgb or
+    /// rgba otherwise. The gray modes turn colors gray, and the indexed
+    /// modes quantize the image to as many colors as the indices reach.
+    /// Indexed files are never filtered.
+    pub color_mode: Option<ColorMode>,
+    /// Type of each channel sample. When None, `encode_to_writer` keeps
+    /// the 16-bit or float samples of the image, and `VedEncoder` takes
+    /// bytes. Quantized images always have 8-bit samples.
+    pub sample: Option<SampleType>,
+    /// Store a CRC-32 with every record of rows and end the file with a
+    /// hash of it, so damage can be found; binary container only.
+    pub checksums: bool,
+    /// Text, EXIF, XMP and ICC profiles to store with the image, such as
+    /// `metadata::from_image` collects from a source file; binary
+    /// container only.
+    pub metadata: Vec<Metadata>,
+}
+
+// Number of rows encoded together, bounding the memory held for encoded
+// rows besides the source image and the palette.
+pub(crate) const STRIP_ROWS: u32 = 64;
+
+// One step of a run-length encoded row. Colors are packed as 0xRRGGBBAA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    // A color from the palette.
+    Index(u32),
+    // A color that is not in the palette.
+    Literal(u32),
+    // This many palette indices packed into bytes, in a binary file whose
+    // pixels are all indices. The indices follow as Index ops.
+    Packed(u64),
+    // Repeat the previous pixel this many more times.
+    Repeat(u64),
+    // Copy this many pixels from the same columns of the row above.
+    Above(u64),
+    // The whole row is the same as this earlier row.
+    Row(u64),
+}
+
+// Pack RGBA bytes into a u32 whose order matches the order of the bytes.
+pub(crate) fn pack(pixel: &[u8]) -> u32 {
+    u32::from_be_bytes(pixel.try_into().unwrap())
+}
+
+// Multiplicative hash for packed colors and palette indices; SipHash
+// dominates encoding and decoding otherwise.
+#[derive(Default)]
+pub(crate) struct ColorHasher(u64);
+
+impl Hasher for ColorHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte.into());
+        }
+    }
+
+    fn write_u32(&mut self, color: u32) {
+        self.write_u64(color.into());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0 ^ value).wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(26);
+    }
+
+    fn write_usize(&mut self, index: usize) {
+        self.write_u64(index as u64);
+    }
+}
+
+// Map keyed by packed colors.
+pub(crate) type ColorMap<V> = HashMap<u32, V, BuildHasherDefault<ColorHasher>>;
+
+// Serialized size of each token in one container, for choosing the
+// cheapest way to write each pixel. Text sizes include the comma before
+// the token.
+pub(crate) struct Costs {
+    container: Container,
+    layout: ChannelLayout,
+    index_bits: u8,
+    // The longest repeat and copy at each size, longest first, down from
+    // the most pixels one token can cover.
+    repeat_steps: Vec<usize>,
+    copy_steps: Vec<usize>,
+}
+
+impl Costs {
+    pub(crate) fn new(container: Container, layout: ChannelLayout, index_bits: u8, max_len: usize) -> Costs {
+        let mut costs = Costs {
+            container,
+            layout,
+            index_bits,
+            repeat_steps: Vec::new(),
+            copy_steps: Vec::new(),
+        };
+        costs.repeat_steps = steps(max_len, |len| costs.repeat(len));
+        costs.copy_steps = steps(max_len, |len| costs.copy(len));
+        costs
+    }
+
+    pub(crate) fn index(&self, index: usize) -> usize {
+        match self.container {
+            Container::Text => 1 + index_len(index),
+            Container::Binary => binary::varint_len((index as u64) << 2),
+        }
+    }
+
+    pub(crate) fn literal(&self) -> usize {
+        match self.container {
+            Container::Text => 2 + 2 * self.layout.channels(),
+            Container::Binary => 1 + self.layout.channels(),
+        }
+    }
+
+    // Whether every pixel is a palette index.
+    fn indexed(&self) -> bool {
+        self.index_bits > 0
+    }
+
+    // Whether indices can be packed, which only binary files do.
+    fn packs(&self) -> bool {
+        self.container == Container::Binary && self.indexed()
+    }
+
+    fn packed(&self, len: usize) -> usize {
+        binary::varint_len((len as u64 - 1) << 2) + binary::packed_len(len, self.index_bits)
+    }
+
+    fn repeat(&self, len: usize) -> usize {
+        match self.container {
+            Container::Text if len >= 4 => 2 + decimal_len(len as u64),
+            Container::Text => len,
+            Container::Binary => binary::varint_len((len as u64 - 1) << 2),
+        }
+    }
+
+    fn copy(&self, len: usize) -> usize {
+        match self.container {
+            Container::Text => 2 + decimal_len(len as u64),
+            Container::Binary => binary::varint_len((len as u64 - 1) << 3),
+        }
+    }
+
+    // Size of a palette entry, "index=color," in a text file.
+    fn palette_entry(&self, index: usize) -> usize {
+        match self.container {
+            Container::Text => index_len(index) + 2 + 2 * self.layout.channels(),
+            Container::Binary => self.layout.channels(),
+        }
+    }
+}
+
+// The longest length at each size of a token whose size never shrinks as
+// it gets longer, from `max` down.
+fn steps(max: usize, cost: impl Fn(usize) -> usize) -> Vec<usize> {
+    let longest_cheaper = |len: usize| {
+        let (mut cheaper, mut dearer) = (0, len);
+        while dearer - cheaper > 1 {
+            let mid = (cheaper + dearer) / 2;
+            if cost(mid) < cost(len) { cheaper = mid } else { dearer = mid }
+        }
+        (cheaper > 0).then_some(cheaper)
+    };
+    std::iter::successors((max > 0).then_some(max), |&len| longest_cheaper(len)).collect()
+}
+
+// Lengths worth trying for a token that can cover up to `max` pixels:
+// `max` and the longest length at each smaller size.
+fn lengths(max: usize, steps: &[usize]) -> impl Iterator<Item = usize> + '_ {
+    let shorter = steps.iter().copied().skip_while(move |&len| len >= max);
+    (max > 0).then_some(max).into_iter().chain(shorter)
+}
+
+// Most indices packed into one op: the most a two-byte varint counts.
+const MAX_PACKED: usize = 4096;
+
+// Lengths worth trying for packed indices with `max` pixels left: one to
+// three bytes of them, then doubling up to MAX_PACKED, and everything left
+// when that is shorter.
+fn packed_lengths(max: usize, bits: u8) -> impl Iterator<Item = usize> {
+    let per_byte = 8 / bits as usize;
+    let longest = max.min(MAX_PACKED);
+    let bytes = (1..4).chain(std::iter::successors(Some(4), |&bytes| Some(bytes * 2)));
+    bytes.map(move |bytes| bytes * per_byte).take_while(move |&len| len < longest).chain(std::iter::once(longest))
+}
+
+// Hash of a whole row, to find rows that repeat an earlier one.
+pub(crate) fn hash_row(row: &[u8]) -> u64 {
+    let mut hasher = ColorHasher::default();
+    let mut words = row.chunks_exact(8);
+    for word in &mut words {
+        hasher.write_u64(u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    hasher.write(words.remainder());
+    hasher.finish()
+}
+
+//ANCHOR - Encode
+/// Encode an image into the bytes of a binary .ved file.
+pub fn encode_image(img: &DynamicImage) -> Vec<u8> {
+    encode_image_with(img, &EncodeOptions::default())
+}
+
+/// Encode an image into the bytes of a .ved file in the chosen container.
+pub fn encode_image_with(img: &DynamicImage, options: &EncodeOptions) -> Vec<u8> {
+    let mut output = Vec::new();
+    encode_to_writer(img, &mut output, options).expect("writing to a Vec cannot fail");
+    output
+}
+
+/// Encode an image straight into a writer, one strip of rows at a time.
+pub fn encode_to_writer<W: Write>(img: &DynamicImage, writer: W, options: &EncodeOptions) -> io::Result<W> {
+    let sample = match options.quantize {
+        Some(_) => SampleType::U8,
+        None => options.sample.unwrap_or(SampleType::of(img.color())),
+    };
+    // Wider samples are turned gray before they are split into byte planes.
+    let gray;
+    let img = match options.color_mode {
+        Some(ColorMode::L | ColorMode::La) if sample != SampleType::U8 => {
+            gray = to_gray(img, sample);
+            &gray
+        }
+        _ => img,
+    };
+    let (rgba, has_alpha) = match sample {
+        SampleType::U8 => {
+            let rgba = match (&options.quantize, img) {
+                (Some(quantize), _) => Cow::Owned(quantize::quantize(img, quantize)),
+                (None, DynamicImage::ImageRgba8(rgba)) => Cow::Borrowed(rgba),
+                (None, _) => Cow::Owned(img.to_rgba8()),
+            };
+            // Only store alpha when some pixel is not fully opaque.
+            let has_alpha = img.color().has_alpha() && rgba.pixels().any(|pixel| pixel[3] != 255);
+            (rgba, has_alpha)
+        }
+        SampleType::U16 => {
+            let wide = img.to_rgba16();
+            let has_alpha = img.color().has_alpha() && wide.pixels().any(|pixel| pixel[3] != u16::MAX);
+            (Cow::Owned(byte_planes(DynamicImage::ImageRgba16(wide), sample)), has_alpha)
+        }
+        SampleType::F32 => {
+            let wide = img.to_rgba32f();
+            let has_alpha = img.color().has_alpha() && wide.pixels().any(|pixel| pixel[3] != 1.0);
+            (Cow::Owned(byte_planes(DynamicImage::ImageRgba32F(wide), sample)), has_alpha)
+        }
+    };
+    let (rgba, mode) = fit_colors(rgba, has_alpha, sample, options)?;
+    let (stored_width, height) = rgba.dimensions();
+    let pixels = rgba.as_raw();
+    let row_len = (stored_width as usize) * 4;
+    let index_bits = mode.index_bits().unwrap_or(0);
+    let layout = mode.layout(is_gray(&rgba), has_alpha);
+
+    // Filtered files get their palette from the residuals, so filter first.
+    let filtering = options.container == Container::Binary && options.filtering != Filtering::Off && index_bits == 0;
+    let filtered = (filtering && row_len > 0).then(|| {
+        let channels = layout.filtered_channels();
+        filter::filter_rows(options.filtering, pixels, None, row_len, 0, channels, options.row_index)
+    });
+    let rows = filtered.as_ref().map_or(&pixels[..], |(_, residuals)| residuals);
+    let palette = palette_of(rows, stored_width, layout, index_bits, options);
+
+    let options = EncodeOptions { color_mode: Some(mode), sample: Some(sample), ..options.clone() };
+    let width = stored_width / sample.bytes() as u32;
+    let mut encoder = VedEncoder::new(writer, width, height, has_alpha, &palette, &options)?;
+    let strip_len = row_len * (STRIP_ROWS as usize);
+    if strip_len > 0 {
+        for (i, strip) in rows.chunks(strip_len).enumerate() {
+            let first = i * STRIP_ROWS as usize;
+            let filters = filtered.as_ref().map_or(&[][..], |(filters, _)| &filters[first..first + strip.len() / row_len]);
+            encoder.write_filtered(strip, filters)?;
+        }
+    }
+    encoder.finish()
+}
+
+// Pick the color mode of an image, unless `options` names one, and make
+// the pixels fit it: gray for the gray modes, and no more colors than the
+// palette of an indexed file can hold.
+pub(crate) fn fit_colors<'a>(
+    rgba: Cow<'a, RgbaImage>,
+    has_alpha: bool,
+    sample: SampleType,
+    options: &EncodeOptions
+) -> io::Result<(Cow<'a, RgbaImage>, ColorMode)> {
+    let mode = options.color_mode.unwrap_or_else(|| detect_color_mode(&rgba, has_alpha, options.max_palette));
+    let rgba = match (mode, mode.index_bits()) {
+        (ColorMode::L | ColorMode::La, _) if !is_gray(&rgba) => {
+            Cow::Owned(to_gray(&DynamicImage::ImageRgba8(rgba.into_owned()), SampleType::U8).to_rgba8())
+        }
+        (_, Some(bits)) => {
+            let colors = options.max_palette.map_or(1 << bits, |max| max.clamp(1, 1 << bits));
+            if count_colors(&rgba, colors) <= colors {
+                rgba
+            } else if sample != SampleType::U8 {
+                // Byte planes cannot be quantized without mixing up the samples.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("image has more {} colors than fit {}-bit indices", sample.name(), bits)
+                ));
+            } else {
+                let quantize = Quantize { colors, ..options.quantize.unwrap_or_default() };
+                Cow::Owned(quantize::quantize(&DynamicImage::ImageRgba8(rgba.into_owned()), &quantize))
+            }
+        }
+        _ => rgba,
+    };
+    Ok((rgba, mode))
+}
+
+// The palette of rows of `width` RGBA pixels: the colors that save more
+// than they cost, or every color of an indexed file.
+pub(crate) fn palette_of(
+    rows: &[u8],
+    width: u32,
+    layout: ChannelLayout,
+    index_bits: u8,
+    options: &EncodeOptions
+) -> Vec<[u8; 4]> {
+    let costs = Costs::new(options.container, layout, index_bits, width as usize);
+    let max_palette = if index_bits > 0 { None } else { options.max_palette };
+    build_palette(rows, max_palette, &costs).into_iter().map(u32::to_be_bytes).collect()
+}
+
+// The byte planes of an image of wider samples, as an image of RGBA8
+// pixels `sample.bytes()` times as wide.
+fn byte_planes(img: DynamicImage, sample: SampleType) -> RgbaImage {
+    let (width, height) = (img.width(), img.height());
+    let planes = sample::split_planes(img.as_bytes(), width as usize, sample);
+    RgbaImage::from_raw(width * sample.bytes() as u32, height, planes).expect("planes fit the image")
+}
+
+// The image with every color turned gray, keeping its alpha and samples.
+fn to_gray(img: &DynamicImage, sample: SampleType) -> DynamicImage {
+    match sample {
+        SampleType::U8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        SampleType::U16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        SampleType::F32 => {
+            // `image` has no float gray type, so the gray goes into every channel.
+            let gray = img.to_luma_alpha32f();
+            let rgba = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                let [l, a] = gray.get_pixel(x, y).0;
+                Rgba([l, l, l, a])
+            });
+            DynamicImage::ImageRgba32F(rgba)
+        }
+    }
+}
+
+// Whether every pixel is gray.
+pub(crate) fn is_gray(img: &RgbaImage) -> bool {
+    img.as_raw().par_chunks_exact(4).all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2])
+}
+
+// Number of distinct colors of an image, counting no further than one
+// past `limit`.
+fn count_colors(img: &RgbaImage, limit: usize) -> usize {
+    let mut colors = ColorMap::default();
+    for pixel in img.pixels() {
+        colors.insert(pack(&pixel.0), ());
+        if colors.len() > limit {
+            break;
+        }
+    }
+    colors.len()
+}
+
+// The color mode that stores an image exactly in the least space: indices
+// of up to 4 bits for a few colors, a gray channel when every pixel is
+// gray, 8-bit indices for up to 256 colors and rgb or rgba otherwise.
+fn detect_color_mode(img: &RgbaImage, alpha: bool, max_palette: Option<usize>) -> ColorMode {
+    let limit = max_palette.map_or(256, |max| max.min(256));
+    let count = count_colors(img, limit);
+    let fits = |bits: u32| count <= (1 << bits).min(limit);
+    match (fits(1), fits(2), fits(4)) {
+        (true, _, _) => ColorMode::Indexed1,
+        (_, true, _) => ColorMode::Indexed2,
+        (_, _, true) => ColorMode::Indexed4,
+        _ if is_gray(img) && alpha => ColorMode::La,
+        _ if is_gray(img) => ColorMode::L,
+        _ if count <= limit => ColorMode::Indexed8,
+        _ if alpha => ColorMode::Rgba,
+        _ => ColorMode::Rgb,
+    }
+}
+
+// Count how often a run of each color starts and put the colors in the
+// palette most frequent first and then by color, as long as the entry
+// costs less than it saves, up to `max_len` of them. Indexed files get
+// every color.
+fn build_palette(pixels: &[u8], max_len: Option<usize>, costs: &Costs) -> Vec<u32> {
+    // Count runs in parallel chunks, merging the counts as we go.
+    let pixel_count = pixels
+        .par_chunks(4 << 16)
+        .fold(ColorMap::default, |mut local_count: ColorMap<u32>, chunk| {
+            let mut colors = chunk.chunks_exact(4).map(|pixel| costs.layout.normalize(pack(pixel)));
+            let Some(mut run_color) = colors.next() else {
+                return local_count;
+            };
+            for color in colors {
+                if color != run_color {
+                    *local_count.entry(run_color).or_insert(0) += 1;
+                    run_color = color;
+                }
+            }
+            *local_count.entry(run_color).or_insert(0) += 1;
+            local_count
+        })
+        .reduce(ColorMap::default, |mut total, local_count| {
+            for (color, count) in local_count {
+                *total.entry(color).or_insert(0) += count;
+            }
+            total
+        });
+
+    // HashMap order changes from run to run, so break ties by color value to
+    // give the same image the same palette every time.
+    let mut counts: Vec<(u32, u32)> = pixel_count.into_iter().collect();
+    counts.sort_unstable_by_key(|&(color, amount)| (Reverse(amount), color));
+    // Later colors are rarer and get longer indices, so the first one that
+    // is not worth an entry ends the palette.
+    counts
+        .into_iter()
+        .take(max_len.unwrap_or(usize::MAX))
+        .enumerate()
+        .take_while(|&(index, (_, amount))| {
+            if costs.indexed() {
+                return true;
+            }
+            let saving = costs.literal().saturating_sub(costs.index(index));
+            (amount as usize).saturating_mul(saving) > costs.palette_entry(index)
+        })
+        .map(|(_, (color, _))| color)
+        .collect()
+}
+
+// Encode one row of RGBA bytes into the cheapest ops under `costs`,
+// ignoring the channels the file does not store. Each pixel is a palette
+// index or a literal, or is covered by repeating the pixel before it or by
+// copying the row above. Binary indexed files can also pack indices.
+// `row` may also be several rows of `width` pixels encoded as one, where
+// each copies from the one before.
+pub(crate) fn encode_row(
+    row: &[u8],
+    above: Option<&[u8]>,
+    width: usize,
+    variables: &ColorMap<u32>,
+    costs: &Costs,
+    ops: &mut Vec<Op>
+) {
+    ops.clear();
+    let normalize = |pixel: &[u8]| costs.layout.normalize(pack(pixel));
+    let colors: Vec<u32> = row.chunks_exact(4).map(normalize).collect();
+    let above: Option<Vec<u32>> = above.map(|above| above.chunks_exact(4).map(normalize).collect());
+    let same_as_above = |i: usize| match i.checked_sub(width) {
+        Some(k) => colors[k] == colors[i],
+        None => above.as_ref().is_some_and(|above| above[i] == colors[i]),
+    };
+
+    // Working back from the end, the cheapest way to write the pixels from
+    // each one on. Cutting a token's first pixel off never makes it dearer,
+    // so that cost never grows to the right, and of the lengths a token
+    // can have at one size only the longest is worth trying.
+    let mut best = vec![(0, Op::Repeat(0)); colors.len() + 1];
+    let (mut run, mut copy) = (0, 0);
+    for i in (0..colors.len()).rev() {
+        run = if i > 0 && colors[i] == colors[i - 1] { run + 1 } else { 0 };
+        copy = if same_as_above(i) { copy + 1 } else { 0 };
+        let mut choice = (usize::MAX, Op::Repeat(0));
+        for len in lengths(run, &costs.repeat_steps) {
+            let cost = costs.repeat(len) + best[i + len].0;
+            if cost < choice.0 {
+                choice = (cost, Op::Repeat(len as u64));
+            }
+        }
+        for len in lengths(copy, &costs.copy_steps) {
+            let cost = costs.copy(len) + best[i + len].0;
+            if cost < choice.0 {
+                choice = (cost, Op::Above(len as u64));
+            }
+        }
+        let color = match variables.get(&colors[i]) {
+            Some(&index) if costs.indexed() || costs.index(index as usize) <= costs.literal() => {
+                (costs.index(index as usize), Op::Index(index))
+            }
+            _ => (costs.literal(), Op::Literal(colors[i])),
+        };
+        if color.0 + best[i + 1].0 < choice.0 {
+            choice = (color.0 + best[i + 1].0, color.1);
+        }
+        if costs.packs() {
+            for len in packed_lengths(colors.len() - i, costs.index_bits) {
+                let cost = costs.packed(len) + best[i + len].0;
+                if cost < choice.0 {
+                    choice = (cost, Op::Packed(len as u64));
+                }
+            }
+        }
+        best[i] = choice;
+    }
+
+    let mut i = 0;
+    while i < colors.len() {
+        let op = best[i].1;
+        ops.push(op);
+        i += match op {
+            Op::Packed(len) => {
+                let indices = colors[i..i + len as usize].iter().map(|color| Op::Index(variables[color]));
+                ops.extend(indices);
+                len as usize
+            }
+            Op::Repeat(len) | Op::Above(len) => len as usize,
+            _ => 1,
+        };
+    }
+}
+
+// Append the stored channels of a color in hex, as "RRGGBB" in an rgb file.
+fn push_hex(out: &mut Vec<u8>, color: u32, layout: ChannelLayout) {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    for byte in &layout.store(color.to_be_bytes())[..layout.channels()] {
+        out.push(DIGITS[(byte >> 4) as usize]);
+        out.push(DIGITS[(byte & 0xF) as usize]);
+    }
+}
+
+// Digits of palette indices in text files, from 0 to 63.
+pub(crate) const INDEX_DIGITS: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+
+// Number of digits of a palette index in a text file.
+fn index_len(mut index: usize) -> usize {
+    let mut len = 1;
+    while index >= 64 {
+        index /= 64;
+        len += 1;
+    }
+    len
+}
+
+// Number of digits of a number in decimal.
+fn decimal_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 10 {
+        value /= 10;
+        len += 1;
+    }
+    len
+}
+
+// Append a palette index in base 64.
+fn push_index(out: &mut Vec<u8>, mut index: usize) {
+    let start = out.len();
+    loop {
+        out.push(INDEX_DIGITS[index % 64]);
+        index /= 64;
+        if index == 0 {
+            break;
+        }
+    }
+    out[start..].reverse();
+}
+
+// Append a number in decimal.
+fn push_decimal(out: &mut Vec<u8>, mut value: u64) {
+    let mut digits = [0; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&digits[start..]);
+}
+
+// Write the first two lines of the text format.
+/*
+  ┌────────────────────────────────────────────────────────────────────────────┐
+  │ The text .ved file format is as follows:                                   │
+  │ 1. The first line contains the format version, the image dimensions and    │
+  │ the channel layout: "ved2,width,height,rgb". The layout is "l" (gray),     │
+  │ "la" (gray and alpha), "rgb" or "rgba". A fifth field "pN" marks a file    │
+  │ whose pixels are all N-bit palette indices, N being 1, 2, 4 or 8.          │
+  │ 2. The second line contains a list of frequently used colors in the        │
+  │ format                                                                     │
+  │ "index=color".                                                             │
+  │ 3. Each subsequent line contains a row of the image, where each pixel is   │
+  │ represented by an index or a                                               │
+  │ color.                                                                     │
+  │ 4. Pixels with the same color are represented by an empty string.          │
+  │ 5. Pixels with a color not in the frequently used colors list are          │
+  │ represented by the color                                                   │
+  │ itself, prefixed with "#".                                                 │
+  │ 6. The image is encoded using run-length encoding: "*N" repeats the        │
+  │ previous pixel N times.                                                    │
+  │ 7. Colors are "RRGGBB", "RRGGBBAA" in an rgba file, "LL" in an l file and  │
+  │ "LLAA" in an la file. Indices are in base 64, with the digits 0-9, A-Z,    │
+  │ a-z, "-" and "_".                                                          │
+  │ 8. "^N" copies N pixels from the same columns of the row above.            │
+  │ 9. A row that is just "=K" is the same as row K, counted from 0. Rows can  │
+  │ only copy from the ROW_WINDOW rows above them.                             │
+  │ 10. Files with a "pN" field have no "#" colors, and at most 2^N palette    │
+  │ entries.                                                                   │
+  │ 11. A last field "u16" or "f32" marks 16-bit or float samples. Each row    │
+  │ then holds the byte planes of the binary format: width pixels of the most  │
+  │ significant byte of every sample, then width pixels of the next byte, and  │
+  │ so on.                                                                     │
+  │                                                                            │
+  └────────────────────────────────────────────────────────────────────────────┘
+ */
+pub(crate) fn write_text_header<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    layout: ChannelLayout,
+    index_bits: u8,
+    sample: SampleType,
+    palette: &[[u8; 4]]
+) -> io::Result<()> {
+    // First line: version, image dimensions, channel layout, index size and
+    // sample type.
+    write!(writer, "ved{},{},{},{}", crate::TEXT_VERSION, width, height, layout.name())?;
+    if index_bits > 0 {
+        write!(writer, ",p{}", index_bits)?;
+    }
+    if sample != SampleType::U8 {
+        write!(writer, ",{}", sample.name())?;
+    }
+    writeln!(writer)?;
+
+    let mut line = Vec::with_capacity(palette.len() * 12 + 1);
+    for (i, &color) in palette.iter().enumerate() {
+        if i > 0 {
+            line.push(b',');
+        }
+        push_index(&mut line, i);
+        line.push(b'=');
+        push_hex(&mut line, u32::from_be_bytes(color), layout);
+    }
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+// Append one row of the text format, including its newline. Short repeats
+// are written as empty tokens, longer ones as "*N", copies from the row
+// above as "^N" and a row the same as row K as "=K".
+pub(crate) fn write_text_row(ops: &[Op], layout: ChannelLayout, out: &mut Vec<u8>) {
+    for (i, &op) in ops.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        match op {
+            Op::Index(index) => push_index(out, index as usize),
+            Op::Literal(color) => {
+                out.push(b'#');
+                push_hex(out, color, layout);
+            }
+            Op::Packed(_) => unreachable!("text files do not pack indices"),
+            Op::Repeat(count) if count >= 4 => {
+                out.push(b'*');
+                push_decimal(out, count);
+            }
+            // The comma above already ends the first empty token.
+            Op::Repeat(count) => out.extend(std::iter::repeat_n(b',', count as usize - 1)),
+            Op::Above(count) => {
+                out.push(b'^');
+                push_decimal(out, count);
+            }
+            Op::Row(k) => {
+                out.push(b'=');
+                push_decimal(out, k);
+            }
+        }
+    }
+    out.push(b'\n');
+}
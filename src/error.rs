@@ -0,0 +1,111 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading a .ved file.
+///
+/// For text files, lines and columns are 1-based and point into the text of
+/// the file; the column is where the offending token or palette entry starts.
+/// Binary files report the byte offset of the problem instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VedError {
+    /// The file is not valid UTF-8.
+    InvalidUtf8 { line: usize, column: usize },
+    /// The first line is missing or is not a valid header.
+    BadHeader { line: usize, message: String },
+    /// The header names a format version newer than this decoder.
+    UnsupportedVersion(u32),
+    /// The palette line is missing.
+    MissingPalette { line: usize },
+    /// A palette entry is not of the form "index=color".
+    BadPaletteEntry { line: usize, column: usize, entry: String },
+    /// A row token is neither an index, a literal color, a run nor empty.
+    BadToken { line: usize, column: usize, token: String },
+    /// A row refers to an index missing from the palette.
+    UnknownIndex { line: usize, column: usize, index: usize },
+    /// A row repeats the previous pixel before any pixel was given.
+    NothingToRepeat { line: usize, column: usize },
+    /// A row expands to more or fewer pixels than the header width.
+    RowWidthMismatch { line: usize, expected: u32, found: u64 },
+    /// The file has more rows than the header height.
+    TooManyRows { line: usize, expected: u32 },
+    /// The file ends before the header height is reached.
+    TooFewRows { line: usize, expected: u32, found: u32 },
+    /// A binary file ends in the middle of a value.
+    UnexpectedEof { offset: usize },
+    /// A binary file holds a value that is not allowed where it appears.
+    BadBinary { offset: usize, message: String },
+    /// A region to decode does not lie within the image.
+    RegionOutOfBounds { x: u32, y: u32, width: u32, height: u32 },
+    /// The rows of a record do not match the checksum stored with them.
+    CorruptRows { offset: usize, first: u32, count: u32 },
+    /// The hash at the end of a binary file does not match the file.
+    CorruptFile { offset: usize },
+    /// The file is larger in some way than the decode limits allow.
+    LimitExceeded { what: &'static str, value: u64, limit: u64 },
+    /// Reading a streamed file failed.
+    Io { kind: io::ErrorKind, message: String },
+}
+
+impl fmt::Display for VedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VedError::InvalidUtf8 { line, column } => {
+                write!(f, "{}:{}: invalid UTF-8", line, column)
+            }
+            VedError::BadHeader { line, message } => {
+                write!(f, "{}: bad header: {}", line, message)
+            }
+            VedError::UnsupportedVersion(version) => {
+                write!(f, "unsupported .ved version {}", version)
+            }
+            VedError::MissingPalette { line } => write!(f, "{}: missing palette line", line),
+            VedError::BadPaletteEntry { line, column, entry } => {
+                write!(f, "{}:{}: bad palette entry '{}'", line, column, entry)
+            }
+            VedError::BadToken { line, column, token } => {
+                write!(f, "{}:{}: bad token '{}'", line, column, token)
+            }
+            VedError::UnknownIndex { line, column, index } => {
+                write!(f, "{}:{}: palette index {} is not defined", line, column, index)
+            }
+            VedError::NothingToRepeat { line, column } => {
+                write!(f, "{}:{}: run before the first pixel of the row", line, column)
+            }
+            VedError::RowWidthMismatch { line, expected, found } => {
+                write!(f, "{}: row has {} pixels, expected {}", line, found, expected)
+            }
+            VedError::TooManyRows { line, expected } => {
+                write!(f, "{}: more than the {} rows given in the header", line, expected)
+            }
+            VedError::TooFewRows { line, expected, found } => {
+                write!(f, "{}: file ends after {} of {} rows", line, found, expected)
+            }
+            VedError::UnexpectedEof { offset } => {
+                write!(f, "byte {}: unexpected end of file", offset)
+            }
+            VedError::BadBinary { offset, message } => write!(f, "byte {}: {}", offset, message),
+            VedError::RegionOutOfBounds { x, y, width, height } => {
+                write!(f, "region {}x{} at {},{} is outside the image", width, height, x, y)
+            }
+            VedError::CorruptRows { offset, first, count: 1 } => {
+                write!(f, "row {} at byte {} does not match its checksum", first, offset)
+            }
+            VedError::CorruptRows { offset, first, count } => {
+                write!(f, "rows {} to {} at byte {} do not match their checksum", first, first + count - 1, offset)
+            }
+            VedError::CorruptFile { offset } => write!(f, "file hash at byte {} does not match the file", offset),
+            VedError::LimitExceeded { what, value, limit } => {
+                write!(f, "{} {} exceeds the limit of {}", what, value, limit)
+            }
+            VedError::Io { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VedError {}
+
+impl From<io::Error> for VedError {
+    fn from(error: io::Error) -> VedError {
+        VedError::Io { kind: error.kind(), message: error.to_string() }
+    }
+}
@@ -0,0 +1,223 @@
+use rayon::prelude::*;
+use crate::encode::pack;
+
+/// PNG-style predictor applied to a row before run-length encoding. Each
+/// channel is stored as its difference, modulo 256, from a prediction made
+/// from the pixel to the left and the pixels above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Filter {
+    /// No prediction.
+    #[default]
+    None,
+    /// Predict the pixel to the left.
+    Sub,
+    /// Predict the pixel above.
+    Up,
+    /// Predict the mean of the pixels to the left and above.
+    Average,
+    /// Predict whichever of left, above and above-left is closest to
+    /// left + above - above-left.
+    Paeth,
+}
+
+/// Which predictors the encoder applies; binary container only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Filtering {
+    /// Rows are stored as they are.
+    #[default]
+    Off,
+    /// Every row uses the same predictor.
+    Fixed(Filter),
+    /// Each row uses the predictor that gives it the fewest runs.
+    Adaptive,
+}
+
+// With a row index, every this many rows only use filters that do not
+// depend on the row above and copy nothing from the rows above, and rows
+// never copy from before the last such row, so a region can be decoded
+// without starting at the top of the image.
+pub(crate) const ANCHOR_ROWS: u32 = 64;
+
+const FILTERS: [Filter; 5] = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth];
+
+impl Filter {
+    // Value stored in front of each row of a filtered file.
+    pub(crate) fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Filter> {
+        FILTERS.get(id as usize).copied()
+    }
+
+    // Whether the row can be reconstructed without the row above.
+    pub(crate) fn is_standalone(self) -> bool {
+        matches!(self, Filter::None | Filter::Sub)
+    }
+}
+
+impl Filtering {
+    /// Look up a filtering by its command-line name: "off", "adaptive" or
+    /// the name of a single filter.
+    pub fn from_name(name: &str) -> Option<Filtering> {
+        match name {
+            "off" => Some(Filtering::Off),
+            "adaptive" => Some(Filtering::Adaptive),
+            "none" => Some(Filtering::Fixed(Filter::None)),
+            "sub" => Some(Filtering::Fixed(Filter::Sub)),
+            "up" => Some(Filtering::Fixed(Filter::Up)),
+            "average" => Some(Filtering::Fixed(Filter::Average)),
+            "paeth" => Some(Filtering::Fixed(Filter::Paeth)),
+            _ => None,
+        }
+    }
+}
+
+// The prediction for one channel from the left, above and above-left values.
+fn predict(filter: Filter, left: u8, up: u8, up_left: u8) -> u8 {
+    match filter {
+        Filter::None => 0,
+        Filter::Sub => left,
+        Filter::Up => up,
+        Filter::Average => ((left as u16 + up as u16) / 2) as u8,
+        Filter::Paeth => {
+            let estimate = left as i16 + up as i16 - up_left as i16;
+            let (to_left, to_up, to_up_left) =
+                ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+            if to_left <= to_up && to_left <= to_up_left {
+                left
+            } else if to_up <= to_up_left {
+                up
+            } else {
+                up_left
+            }
+        }
+    }
+}
+
+//ANCHOR - Filter
+// Replace the first `channels` bytes of every RGBA pixel of `row` by their
+// difference from the prediction, writing into `out`. The remaining bytes
+// are copied. `prev` is the original row above, if any.
+pub(crate) fn filter_row(filter: Filter, row: &[u8], prev: Option<&[u8]>, channels: usize, out: &mut [u8]) {
+    out.copy_from_slice(row);
+    if filter == Filter::None {
+        return;
+    }
+    for i in 0..row.len() {
+        if i % 4 >= channels {
+            continue;
+        }
+        let left = if i >= 4 { row[i - 4] } else { 0 };
+        let up = prev.map_or(0, |prev| prev[i]);
+        let up_left = if i >= 4 { prev.map_or(0, |prev| prev[i - 4]) } else { 0 };
+        out[i] = row[i].wrapping_sub(predict(filter, left, up, up_left));
+    }
+}
+
+// Undo `filter_row` in place. `prev` is the reconstructed row above, if any.
+pub(crate) fn unfilter_row(filter: Filter, row: &mut [u8], prev: Option<&[u8]>, channels: usize) {
+    if filter == Filter::None {
+        return;
+    }
+    for i in 0..row.len() {
+        if i % 4 >= channels {
+            continue;
+        }
+        let left = if i >= 4 { row[i - 4] } else { 0 };
+        let up = prev.map_or(0, |prev| prev[i]);
+        let up_left = if i >= 4 { prev.map_or(0, |prev| prev[i - 4]) } else { 0 };
+        row[i] = row[i].wrapping_add(predict(filter, left, up, up_left));
+    }
+}
+
+// How well a filtered row suits run-length encoding: the number of runs,
+// then the sum of the residuals as signed bytes, lower being better.
+fn cost(row: &[u8], channels: usize) -> (usize, u64) {
+    let opaque = if channels == 4 { 0 } else { 0xFF };
+    let mut runs = 0;
+    let mut last = None;
+    let mut sum = 0;
+    for pixel in row.chunks_exact(4) {
+        let color = pack(pixel) | opaque;
+        if last != Some(color) {
+            runs += 1;
+            last = Some(color);
+        }
+        sum += pixel[..channels].iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum::<u64>();
+    }
+    (runs, sum)
+}
+
+// Filter a row as `filtering` asks, writing the residuals into `out`, and
+// return the filter used. With `standalone` only filters that do not
+// depend on the row above are tried.
+pub(crate) fn apply(
+    filtering: Filtering,
+    row: &[u8],
+    prev: Option<&[u8]>,
+    channels: usize,
+    standalone: bool,
+    out: &mut [u8]
+) -> Filter {
+    let candidates = match filtering {
+        Filtering::Off => &FILTERS[..1],
+        Filtering::Fixed(filter) if standalone && !filter.is_standalone() => &FILTERS[1..2],
+        Filtering::Fixed(filter) => &FILTERS[filter.id() as usize..][..1],
+        Filtering::Adaptive if standalone => &FILTERS[..2],
+        Filtering::Adaptive => &FILTERS[..],
+    };
+    if let [filter] = candidates {
+        filter_row(*filter, row, prev, channels, out);
+        return *filter;
+    }
+
+    let mut best = (Filter::None, (usize::MAX, u64::MAX));
+    let mut scratch = vec![0; row.len()];
+    for &filter in candidates {
+        filter_row(filter, row, prev, channels, &mut scratch);
+        let cost = cost(&scratch, channels);
+        if cost < best.1 {
+            best = (filter, cost);
+            out.copy_from_slice(&scratch);
+        }
+    }
+    best.0
+}
+
+// Filter consecutive rows of RGBA pixels in parallel, returning the filter
+// of each row and the residuals. `prev` is the row above the first one and
+// `first_row` the number of the first row. With `anchors`, every
+// ANCHOR_ROWS-th row is standalone.
+pub(crate) fn filter_rows(
+    filtering: Filtering,
+    rows: &[u8],
+    prev: Option<&[u8]>,
+    row_len: usize,
+    first_row: u32,
+    channels: usize,
+    anchors: bool
+) -> (Vec<Filter>, Vec<u8>) {
+    let mut residuals = vec![0; rows.len()];
+    let filters = residuals
+        .par_chunks_mut(row_len)
+        .enumerate()
+        .map(|(i, out)| {
+            let row = &rows[i * row_len..][..row_len];
+            let above = if i == 0 { prev } else { Some(&rows[(i - 1) * row_len..][..row_len]) };
+            let standalone = anchors && (first_row + i as u32).is_multiple_of(ANCHOR_ROWS);
+            apply(filtering, row, above, channels, standalone, out)
+        })
+        .collect();
+    (filters, residuals)
+}
+
+// Undo the filters of consecutive rows in place, top to bottom. The first
+// row must not depend on the row above.
+pub(crate) fn unfilter_rows(filters: &[Filter], pixels: &mut [u8], row_len: usize, channels: usize) {
+    for (y, &filter) in filters.iter().enumerate() {
+        let (above, rest) = pixels.split_at_mut(y * row_len);
+        let prev = (y > 0).then(|| &above[(y - 1) * row_len..]);
+        unfilter_row(filter, &mut rest[..row_len], prev, channels);
+    }
+}
@@ -0,0 +1,134 @@
+use rayon::prelude::*;
+use crate::error::VedError;
+
+/// How far back a row may copy from: a row can repeat any of this many
+/// rows above it, so decoding one row at a time only has to keep them.
+pub const ROW_WINDOW: usize = 256;
+
+// Rows decoded before row `row`, for the tokens that copy from them.
+// `pixels` holds rows `first` up to `row`, each `row_len` bytes covering
+// the same columns as the row being decoded.
+#[derive(Clone, Copy)]
+pub(crate) struct Above<'a> {
+    pub pixels: &'a [u8],
+    pub row_len: usize,
+    pub first: usize,
+    pub row: usize,
+}
+
+impl<'a> Above<'a> {
+    // No rows to copy from, for a row known not to copy.
+    pub(crate) fn none(row: usize) -> Above<'a> {
+        Above { pixels: &[], row_len: 0, first: row, row }
+    }
+
+    // Row `k`, if it is one of the rows within reach.
+    pub(crate) fn get(&self, k: usize) -> Option<&'a [u8]> {
+        if k >= self.row || k < self.first || k + ROW_WINDOW < self.row {
+            return None;
+        }
+        let start = (k - self.first) * self.row_len;
+        self.pixels.get(start..start + self.row_len)
+    }
+
+    // The row right above, if there is one.
+    pub(crate) fn previous(&self) -> Option<&'a [u8]> {
+        self.row.checked_sub(1).and_then(|k| self.get(k))
+    }
+}
+
+// Where a row is expanded to: the RGBA pixels of its columns from `first`
+// on, and the same columns of the rows above it.
+pub(crate) struct RowOut<'a> {
+    pub first: usize,
+    pub pixels: &'a mut [u8],
+    pub above: Above<'a>,
+}
+
+// The last rows of an image, at least ROW_WINDOW of them once there are
+// that many, kept in one buffer so they can be handed out as an `Above`.
+pub(crate) struct RowHistory {
+    pixels: Vec<u8>,
+    row_len: usize,
+    first: usize,
+    next: usize,
+}
+
+impl RowHistory {
+    pub(crate) fn new(row_len: usize) -> RowHistory {
+        RowHistory { pixels: Vec::new(), row_len, first: 0, next: 0 }
+    }
+
+    pub(crate) fn push(&mut self, row: &[u8]) {
+        // Dropping a whole window at once keeps the cost per row constant.
+        if self.next - self.first == 2 * ROW_WINDOW {
+            self.pixels.drain(..ROW_WINDOW * self.row_len);
+            self.first += ROW_WINDOW;
+        }
+        self.pixels.extend_from_slice(row);
+        self.next += 1;
+    }
+
+    // The rows pushed so far, as seen from the next row.
+    pub(crate) fn above(&self) -> Above<'_> {
+        Above { pixels: &self.pixels, row_len: self.row_len, first: self.first, row: self.next }
+    }
+}
+
+// Expand rows into consecutive `row_len`-byte slices of `pixels` holding
+// columns `first` on, where `rows[i]` is row `first_row + i` of the image
+// and can only copy from rows from `first_row` on. Rows for which `copies`
+// is false are expanded in parallel, then the others in order so that the
+// rows they copy from are done.
+pub(crate) fn expand_rows<T: Sync, R: Send>(
+    pixels: &mut [u8],
+    row_len: usize,
+    first: usize,
+    first_row: usize,
+    rows: &[T],
+    copies: impl Fn(usize, &T) -> bool + Sync,
+    expand: impl Fn(usize, &T, RowOut) -> Result<R, VedError> + Sync
+) -> Result<Vec<R>, VedError> {
+    let reach = |y: usize| y.saturating_sub(ROW_WINDOW).max(first_row);
+    if row_len == 0 {
+        // Zero-width rows hold no pixels but still have to be valid.
+        return rows
+            .par_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let y = first_row + i;
+                let above = Above { pixels: &[], row_len: 0, first: reach(y), row: y };
+                expand(y, row, RowOut { first, pixels: &mut [], above })
+            })
+            .collect();
+    }
+
+    let mut results: Vec<Option<R>> = pixels
+        .par_chunks_mut(row_len)
+        .zip(rows.par_iter())
+        .enumerate()
+        .map(|(i, (out, row))| {
+            let y = first_row + i;
+            if copies(y, row) {
+                return Ok(None);
+            }
+            expand(y, row, RowOut { first, pixels: out, above: Above::none(y) }).map(Some)
+        })
+        .collect::<Result<_, _>>()?;
+
+    for (i, row) in rows.iter().enumerate() {
+        if results[i].is_some() {
+            continue;
+        }
+        let y = first_row + i;
+        let (done, rest) = pixels.split_at_mut(i * row_len);
+        let above = Above {
+            pixels: &done[(reach(y) - first_row) * row_len..],
+            row_len,
+            first: reach(y),
+            row: y,
+        };
+        results[i] = Some(expand(y, row, RowOut { first, pixels: &mut rest[..row_len], above })?);
+    }
+    Ok(results.into_iter().flatten().collect())
+}
@@ -0,0 +1,70 @@
+//! Encoder and decoder for the `.ved` image format.
+//!
+//! ```no_run
+//! let img = image::open("image.png").unwrap();
+//! let bytes = ved::encode_image(&img);
+//! let decoded = ved::decode_bytes(&bytes).unwrap();
+//! decoded.save("decoded.png").unwrap();
+//! ```
+//!
+//! After `ved::codec::register()`, the `image` crate opens .ved files itself:
+//!
+//! ```no_run
+//! ved::codec::register();
+//! let img = image::open("output.ved").unwrap();
+//! img.write_with_encoder(ved::VedImageEncoder::new(std::fs::File::create("copy.ved").unwrap())).unwrap();
+//! ```
+
+/// Version of the binary container written by default.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Version written in the header of text files. Version 0 is the
+/// unversioned legacy format, where palette indices and literal colors
+/// share the same bare-hex token grammar. Version 1 writes palette
+/// indices in decimal and runs as "xN"; version 2 writes indices in base
+/// 64 and runs as "*N".
+pub const TEXT_VERSION: u32 = 2;
+
+pub mod animation;
+pub mod binary;
+pub mod checksum;
+pub mod codec;
+pub mod compression;
+pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod filter;
+pub mod history;
+pub mod metadata;
+pub mod quantize;
+pub mod region;
+pub mod sample;
+pub mod stream;
+
+pub use animation::{ decode_animation, encode_animation, AnimationFrame, Disposal };
+pub use binary::ChannelLayout;
+pub use codec::VedImageEncoder;
+pub use compression::Compression;
+pub use decode::{
+    decode_bytes,
+    decode_bytes_with,
+    read_info,
+    read_metadata,
+    read_stats,
+    verify,
+    DecodeLimits,
+    DecodeOptions,
+    DecodeReport,
+    Repair,
+    SizeStats,
+    VedInfo,
+    VerifyReport,
+};
+pub use encode::{ encode_image, encode_image_with, encode_to_writer, ColorMode, Container, EncodeOptions };
+pub use error::VedError;
+pub use filter::{ Filter, Filtering };
+pub use metadata::Metadata;
+pub use quantize::{ Dither, Quantize, QuantizeMethod };
+pub use region::RegionDecoder;
+pub use sample::SampleType;
+pub use stream::{ VedDecoder, VedEncoder };
@@ -1,241 +1,450 @@
-use image::{ GenericImageView, Pixel, Rgba, RgbaImage };
-use std::fs::File;
-use std::io::{ Write, BufReader, BufRead };
-use std::collections::HashMap;
-use rayon::prelude::*;
+use image::{ DynamicImage, ImageFormat };
+use std::fs;
+use std::io::{ self, BufReader, BufWriter, Cursor, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::process::ExitCode;
 
-//ANCHOR - Encode
-// Read an image file and encode it into a .ved file.
-/* 
-  ┌────────────────────────────────────────────────────────────────────────────┐
-  │ The .ved file format is as follows:                                        │
-  │ 1. The first line contains the image dimensions in the format              │
-  │ "width,height".                                                            │
-  │ 2. The second line contains a list of frequently used colors in the        │
-  │ format                                                                     │
-  │ "index=color".                                                             │
-  │ 3. Each subsequent line contains a row of the image, where each pixel is   │
-  │ represented by an index or a                                               │
-  │ color.                                                                     │
-  │ 4. Pixels with the same color are represented by an empty string.          │
-  │ 5. Pixels with a color not in the frequently used colors list are          │
-  │ represented by the color                                                   │
-  │ itself.                                                                    │
-  │ 6. The image is encoded using run-length encoding.                         │
-  │ 7. The output file is named "output.ved".                                  │
-  │                                                                            │
-  └────────────────────────────────────────────────────────────────────────────┘
- */
-#[allow(dead_code)]
-fn encode() -> Result<(), Box<dyn std::error::Error>> {
-    let img = image::open("image.png")?;
-    let (width, height) = img.dimensions();
-
-    // Process rows in parallel.
-    let row_results: Vec<(String, HashMap<String, u32>)> = (0..height)
-        .into_par_iter()
-        .map(|y| {
-            let mut colors = Vec::with_capacity(width as usize);
-            let mut local_count = HashMap::new();
-            for x in 0..width {
-                let pixel = img.get_pixel(x, y);
-                let channels = pixel.channels();
-                let color = format!("{:02X}{:02X}{:02X}", channels[0], channels[1], channels[2]);
-                *local_count.entry(color.clone()).or_insert(0) += 1;
-                colors.push(color);
-            }
-            (colors.join(","), local_count)
-        })
-        .collect();
+const USAGE: &str = "\
+Usage:
+  ved encode <input> [-o <output>]   Encode an image into a .ved file, keeping its text,
+                                   EXIF, XMP and ICC profile, and every frame of an
+                                   animated GIF or PNG
+             [--text]              Write the text format instead of binary, leaving
+                                   out the metadata
+             [--compression <c>]   Compress a binary file: none (default) or deflate
+             [--max-palette <n>]   Keep at most n colors in the palette
+             [--quantize <m>]      Reduce the colors first: median-cut or k-means
+             [--colors <n>]        Number of colors to quantize to (default 256)
+             [--dither <d>]        Dithering: none (default), floyd-steinberg or ordered
+             [--row-index]         Store where each row starts, for decoding regions
+             [--filter <f>]        Predict rows first: off (default), adaptive, none,
+                                   sub, up, average or paeth
+             [--span-rows]         Let runs continue into the next row
+             [--color <m>]         Store colors as auto (default), l, la, rgb, rgba,
+                                   or p1, p2, p4 or p8 for 1 to 8-bit indices
+             [--sample <s>]        Store samples as u8, u16 or f32 (default: as the image)
+             [--checksums]         Store a checksum for every row and a hash of the file
+  ved decode <input> [-o <output>]   Decode a .ved file into an image, with its metadata
+                                   when it is a PNG; animations become an animated
+                                   GIF or PNG
+             [--lenient]           Pad or truncate rows that do not fit the header, and
+                                   leave damaged rows transparent black
+             [--region <x,y,w,h>]  Decode only the w by h pixels at x,y
+             [--no-limits]         Decode files over 256 megapixels, 65536 pixels wide
+                                   or high, or 1 GiB
+  ved info <input>                   Print information about a .ved file
+  ved verify <input>                 Report which rows of a .ved file are damaged
 
-    // Merge row strings and local pixel counts.
-    let mut new_img = Vec::with_capacity(row_results.len());
-    let mut pixel_count = HashMap::new();
-    for (row, local_count) in row_results {
-        new_img.push(row);
-        for (color, count) in local_count {
-            *pixel_count.entry(color).or_insert(0) += count;
-        }
+Use - as <input> or <output> to read from stdin or write to stdout.";
+
+enum Command {
+    Encode { input: String, output: Option<String>, options: ved::EncodeOptions },
+    Decode { input: String, output: Option<String>, options: ved::DecodeOptions, region: Option<[u32; 4]> },
+    Info { input: String },
+    Verify { input: String },
+    Help,
+}
+
+// Parse the command line into a Command.
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err("missing command".to_string()),
+    };
+    if matches!(command, "-h" | "--help" | "help") {
+        return Ok(Command::Help);
     }
 
-    let mut img_output = Vec::new();
-    // First line: image dimensions.
-    img_output.push(format!("{},{}", width, height));
-
-    // Build a mapping for frequently used colors.
-    let mut counts: Vec<(&String, &u32)> = pixel_count.iter().collect();
-    counts.sort_by(|a, b| b.1.cmp(a.1));
-
-    let mut variables = HashMap::new();
-    let var_line = counts
-        .into_iter()
-        .filter(|&(_, &amount)| amount >= 2)
-        .enumerate()
-        .map(|(i, (color, _))| {
-            variables.insert(color.clone(), i);
-            format!("{}={}", i, color)
-        })
-        .collect::<Vec<String>>()
-        .join(",");
-    img_output.push(var_line);
-
-    // Process rows in parallel for run-length encoding.
-    let encoded_rows: Vec<String> = new_img
-        .into_par_iter()
-        .map(|row| {
-            let mut last_hex = String::new();
-            let mut new_row = Vec::new();
-            for hex in row.split(',') {
-                if hex == last_hex {
-                    new_row.push("".to_string());
-                } else {
-                    last_hex = hex.to_string();
-                    if let Some(index) = variables.get(hex) {
-                        new_row.push(index.to_string());
-                    } else {
-                        new_row.push(hex.to_string());
-                    }
-                }
+    let mut input = None;
+    let mut output = None;
+    let mut decode_options = ved::DecodeOptions::default();
+    let mut region = None;
+    let mut encode_options = ved::EncodeOptions::default();
+    let mut iter = rest.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                output = Some(value.clone());
             }
-
-            // Compress consecutive empty strings in new_row.
-            let mut compressed = Vec::new();
-            let mut empty_count = 0;
-            for token in new_row {
-                if token.is_empty() {
-                    empty_count += 1;
-                } else {
-                    if empty_count >= 4 {
-                        compressed.push(format!("x{}", empty_count));
-                    } else if empty_count > 0 {
-                        for _ in 0..empty_count {
-                            compressed.push("".to_string());
-                        }
-                    }
-                    empty_count = 0;
-                    compressed.push(token.to_string());
-                }
+            "--lenient" if command == "decode" => decode_options.lenient = true,
+            "--no-limits" if command == "decode" => decode_options.limits = ved::DecodeLimits::none(),
+            "--region" if command == "decode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                let numbers: Vec<u32> = value
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("--region expects x,y,w,h, got '{}'", value))?;
+                region = Some(numbers.try_into().map_err(|_| format!("--region expects x,y,w,h, got '{}'", value))?);
             }
-            if empty_count >= 4 {
-                compressed.push(format!("x{}", empty_count));
-            } else if empty_count > 0 {
-                for _ in 0..empty_count {
-                    compressed.push("".to_string());
-                }
+            "--row-index" if command == "encode" => encode_options.row_index = true,
+            "--span-rows" if command == "encode" => encode_options.span_rows = true,
+            "--checksums" if command == "encode" => encode_options.checksums = true,
+            "--filter" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.filtering =
+                    ved::Filtering::from_name(value).ok_or(format!("unknown filter '{}'", value))?;
             }
+            "--color" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.color_mode = match value.as_str() {
+                    "auto" => None,
+                    _ => Some(ved::ColorMode::from_name(value).ok_or(format!("unknown color mode '{}'", value))?),
+                };
+            }
+            "--sample" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.sample =
+                    Some(ved::SampleType::from_name(value).ok_or(format!("unknown sample type '{}'", value))?);
+            }
+            "--text" if command == "encode" => encode_options.container = ved::Container::Text,
+            "--compression" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.compression = ved::Compression::from_name(value)
+                    .ok_or(format!("unknown compression '{}'", value))?;
+            }
+            "--max-palette" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.max_palette = Some(parse_count(arg, value)?);
+            }
+            "--quantize" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.quantize.get_or_insert_with(Default::default).method =
+                    ved::QuantizeMethod::from_name(value)
+                        .ok_or(format!("unknown quantization method '{}'", value))?;
+            }
+            "--colors" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.quantize.get_or_insert_with(Default::default).colors =
+                    parse_count(arg, value)?;
+            }
+            "--dither" if command == "encode" => {
+                let value = iter.next().ok_or(format!("{} requires a value", arg))?;
+                encode_options.quantize.get_or_insert_with(Default::default).dither =
+                    ved::Dither::from_name(value).ok_or(format!("unknown dithering '{}'", value))?;
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option '{}'", flag));
+            }
+            _ if input.is_some() => return Err(format!("unexpected argument '{}'", arg)),
+            _ => input = Some(arg.clone()),
+        }
+    }
+    let input = input.ok_or("missing <input>")?;
 
-            compressed.join(",")
-        })
-        .collect();
+    match command {
+        "encode" => Ok(Command::Encode { input, output, options: encode_options }),
+        "decode" if decode_options.lenient && region.is_some() => {
+            Err("--lenient cannot be combined with --region".to_string())
+        }
+        "decode" => Ok(Command::Decode { input, output, options: decode_options, region }),
+        "info" if output.is_some() => Err("info does not take -o".to_string()),
+        "info" => Ok(Command::Info { input }),
+        "verify" if output.is_some() => Err("verify does not take -o".to_string()),
+        "verify" => Ok(Command::Verify { input }),
+        _ => Err(format!("unknown command '{}'", command)),
+    }
+}
 
-    img_output.extend(encoded_rows);
+// Parse the value of a flag that takes a positive count.
+fn parse_count(flag: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} expects a positive number, got '{}'", flag, value)),
+    }
+}
 
-    let mut file = File::create("output.ved")?;
-    for line in img_output {
-        writeln!(file, "{}", line)?;
+// Read the whole input, where "-" means stdin.
+fn read_input(input: &str) -> io::Result<Vec<u8>> {
+    if input == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(input)
     }
+}
 
-    println!(
-        ".ved File size: {} bytes \n.png file size: {} bytes",
-        file.metadata()?.len(),
-        File::open("image.png")?.metadata()?.len()
-    );
+// Write the output, where "-" means stdout.
+fn write_output(output: &str, bytes: &[u8]) -> io::Result<()> {
+    if output == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    } else {
+        fs::write(output, bytes)
+    }
+}
 
+// Open the output for streaming, where "-" means stdout.
+fn create_output(output: &str) -> io::Result<Box<dyn Write>> {
+    if output == "-" {
+        Ok(Box::new(BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(BufWriter::new(fs::File::create(output)?)))
+    }
+}
+
+// Pick the output path: explicit -o, stdout for stdin input, else the input with a new extension.
+fn output_path(input: &str, output: Option<String>, extension: &str) -> String {
+    output.unwrap_or_else(|| {
+        if input == "-" {
+            "-".to_string()
+        } else {
+            PathBuf::from(input).with_extension(extension).to_string_lossy().into_owned()
+        }
+    })
+}
+
+//ANCHOR - Encode
+// Read an image file and encode it into a .ved file, keeping its metadata
+// and, for an animated GIF or PNG, its frames.
+fn encode(
+    input: &str,
+    output: Option<String>,
+    options: &ved::EncodeOptions
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = read_input(input)?;
+    let options = ved::EncodeOptions { metadata: ved::metadata::from_image(&bytes)?, ..options.clone() };
+    let output = output_path(input, output, "ved");
+    match ved::animation::from_image(&bytes)? {
+        Some(frames) if frames.len() > 1 => {
+            ved::encode_animation(&frames, create_output(&output)?, &options)?;
+        }
+        _ => {
+            let img = image::load_from_memory(&bytes)?;
+            if options.container == ved::Container::Text && !options.metadata.is_empty() {
+                let entries: Vec<String> = options
+                    .metadata
+                    .iter()
+                    .map(|entry| match entry {
+                        ved::Metadata::Text { key, .. } => format!("text {}", key),
+                        entry => entry.name().to_string(),
+                    })
+                    .collect();
+                eprintln!(
+                    "ved: warning: text files hold no metadata, so {} {} left out; drop --text to keep it",
+                    entries.join(", "),
+                    if entries.len() == 1 { "is" } else { "are" }
+                );
+            }
+            ved::encode_to_writer(&img, create_output(&output)?, &options)?;
+        }
+    }
     Ok(())
 }
 
 //ANCHOR - Decode
-// Read a .ved file and decode it into an .png file.
-#[allow(dead_code)]
-fn decode() -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::open("output.ved")?;
-    let mut lines = BufReader::new(file).lines();
-
-    let dimensions = lines.next().ok_or("Missing dimensions")??;
-    let (width, height): (u32, u32) = {
-        let dims: Vec<u32> = dimensions
-            .split(',')
-            .map(|s| s.parse::<u32>().unwrap())
-            .collect();
-        (dims[0], dims[1])
+// Read a .ved file and decode it into an image, PNG unless the output
+// extension says otherwise. A PNG gets the metadata of the file back.
+// Animations are written as an animated GIF or PNG; other formats only
+// get the first frame.
+fn decode(
+    input: &str,
+    output: Option<String>,
+    options: &ved::DecodeOptions,
+    region: Option<[u32; 4]>
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output = output_path(input, output, "png");
+    let format = if output == "-" {
+        ImageFormat::Png
+    } else {
+        ImageFormat::from_path(Path::new(&output)).unwrap_or(ImageFormat::Png)
     };
-
-    let mut img = RgbaImage::new(width, height);
-
-    let variables_line = lines.next().ok_or("Missing variables line")??;
-    let mut variables = HashMap::new();
-    variables_line.split(',').for_each(|var| {
-        let parts: Vec<&str> = var.split('=').collect();
-        if parts.len() == 2 {
-            variables.insert(parts[0].parse::<usize>().unwrap(), parts[1].to_string());
+    let (img, metadata) = match region {
+        // Only the rows of the region, and the metadata before them, are read from a file.
+        Some([x, y, width, height]) if input != "-" => {
+            let metadata = ved::VedDecoder::new(BufReader::new(fs::File::open(input)?), options)?.metadata().to_vec();
+            let img = ved::RegionDecoder::with_limits(fs::File::open(input)?, options.limits)?
+                .decode_region(x, y, width, height)?;
+            (img, metadata)
         }
-    });
-
-    // Collect all remaining lines into a vector.
-    let rows: Vec<String> = lines.collect::<Result<_, _>>()?;
-    // Process each row in parallel.
-    let decoded_rows: Vec<(usize, Vec<Rgba<u8>>)> = rows
-        .par_iter()
-        .enumerate()
-        .map(|(y, row)| {
-            let mut local_last_hex = String::new();
-            let mut expanded_tokens = Vec::new();
-            for token in row.split(',') {
-                if token.starts_with('x') {
-                    let count = token[1..].parse::<usize>().unwrap_or(0);
-                    for _ in 0..count {
-                        expanded_tokens.push(local_last_hex.clone());
-                    }
-                } else if token.is_empty() {
-                    expanded_tokens.push(local_last_hex.clone());
-                } else {
-                    local_last_hex = token.to_string();
-                    expanded_tokens.push(local_last_hex.clone());
+        Some([x, y, width, height]) => {
+            let bytes = read_input(input)?;
+            let metadata = ved::read_metadata(&bytes)?;
+            let img = ved::RegionDecoder::with_limits(Cursor::new(bytes), options.limits)?
+                .decode_region(x, y, width, height)?;
+            (img, metadata)
+        }
+        None => {
+            let bytes = read_input(input)?;
+            // A lenient decode repairs the first frame only.
+            if !options.lenient && ved::read_info(&bytes)?.frames > 1 {
+                if matches!(format, ImageFormat::Gif | ImageFormat::Png) {
+                    return decode_animation(&bytes, &output, format, options.limits);
                 }
+                eprintln!("ved: warning: only the first frame fits a {:?} file", format);
             }
-            let pixels = expanded_tokens
-                .into_iter()
-                .map(|token| {
-                    let color_str = variables
-                        .get(&token.parse::<usize>().unwrap_or(usize::MAX))
-                        .map(|s| s.as_str())
-                        .unwrap_or(&token);
-                    let color_str = if !color_str.starts_with('#') {
-                        format!("#{}", color_str)
-                    } else {
-                        color_str.to_string()
-                    };
-                    if color_str.len() >= 7 {
-                        let r = u8::from_str_radix(&color_str[1..3], 16).unwrap_or(0);
-                        let g = u8::from_str_radix(&color_str[3..5], 16).unwrap_or(0);
-                        let b = u8::from_str_radix(&color_str[5..7], 16).unwrap_or(0);
-                        Rgba([r, g, b, 255])
-                    } else {
-                        println!("Invalid color: {}", color_str);
-                        Rgba([0, 0, 0, 255])
-                    }
-                })
-                .collect();
-            (y, pixels)
-        })
-        .collect();
+            let (img, report) = ved::decode_bytes_with(&bytes, options)?;
+            for repair in &report.repairs {
+                eprintln!("ved: warning: {}", repair);
+            }
+            (img, ved::read_metadata(&bytes)?)
+        }
+    };
+    // Only TIFF and OpenEXR hold float samples; other formats get 16 bits.
+    let img = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+            if !matches!(format, ImageFormat::Tiff | ImageFormat::OpenExr) =>
+        {
+            eprintln!(
+                "ved: warning: a {:?} file cannot hold float samples, so they are written as 16 bits; \
+                 use a .tiff or .exr output to keep them",
+                format
+            );
+            match img.color().has_alpha() {
+                true => DynamicImage::ImageRgba16(img.to_rgba16()),
+                false => DynamicImage::ImageRgb16(img.to_rgb16()),
+            }
+        }
+        img => img,
+    };
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, format)?;
+    let bytes = match format {
+        ImageFormat::Png => ved::metadata::add_to_png(bytes.get_ref(), &metadata),
+        _ => bytes.into_inner(),
+    };
+    write_output(&output, &bytes)?;
+    Ok(())
+}
+
+// Decode every frame of an animation into an animated GIF, or an APNG with
+// the metadata of the file.
+fn decode_animation(
+    bytes: &[u8],
+    output: &str,
+    format: ImageFormat,
+    limits: ved::DecodeLimits
+) -> Result<(), Box<dyn std::error::Error>> {
+    let frames = ved::decode_animation(bytes, limits)?;
+    let mut out = Vec::new();
+    if format == ImageFormat::Gif {
+        ved::animation::to_gif(&frames, &mut out)?;
+    } else {
+        ved::animation::to_apng(&frames, &mut out)?;
+        out = ved::metadata::add_to_png(&out, &ved::read_metadata(bytes)?);
+    }
+    write_output(output, &out)?;
+    Ok(())
+}
 
-    // Write decoded pixels into the image.
-    let mut sorted_rows = decoded_rows;
-    sorted_rows.sort_by_key(|&(y, _)| y);
-    for (y, row_pixels) in sorted_rows {
-        for (x, pixel) in row_pixels.into_iter().enumerate() {
-            img.put_pixel(x as u32, y as u32, pixel);
+//ANCHOR - Info
+// Print the header information of a .ved file.
+fn info(input: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = read_input(input)?;
+    let info = ved::read_info(&bytes)?;
+    let stats = ved::read_stats(&bytes)?;
+    let metadata = ved::read_metadata(&bytes)?;
+    println!("version:    {}", info.version);
+    println!("dimensions: {}x{}", info.width, info.height);
+    println!("samples:    {}", info.sample.name());
+    if info.index_bits > 0 {
+        println!("channels:   {} ({}-bit indices)", info.layout.name(), info.index_bits);
+    } else {
+        println!("channels:   {}", info.layout.name());
+    }
+    println!("palette:    {} colors", info.palette_len);
+    if info.frames > 1 {
+        println!("frames:     {}", info.frames);
+    }
+    let notes: Vec<&str> = [
+        (info.row_index, "indexed"),
+        (info.filtered, "filtered"),
+        (info.spans_rows, "spanning"),
+        (info.checksums, "checksummed"),
+    ]
+    .into_iter()
+        .filter_map(|(set, note)| set.then_some(note))
+        .collect();
+    if notes.is_empty() {
+        println!("rows:       {}", info.rows);
+    } else {
+        println!("rows:       {} ({})", info.rows, notes.join(", "));
+    }
+    for entry in &metadata {
+        match entry {
+            ved::Metadata::Text { key, value } => println!("metadata:   text {}: {}", key, value),
+            ved::Metadata::Exif(data) | ved::Metadata::Icc(data) => {
+                println!("metadata:   {}, {} bytes", entry.name(), data.len())
+            }
+            ved::Metadata::Xmp(xmp) => println!("metadata:   {}, {} bytes", entry.name(), xmp.len()),
         }
     }
+    println!("raw pixels: {} bytes", stats.raw);
+    println!(
+        "run-length: {} bytes (header {}, palette {}, metadata {}, rows {}, frames {}, index {}, checksums {})",
+        stats.run_length(),
+        stats.header,
+        stats.palette,
+        stats.metadata,
+        stats.rows,
+        stats.frames,
+        stats.index,
+        stats.checksums
+    );
+    println!("stored:     {} bytes ({})", stats.file, info.compression.name());
+    Ok(())
+}
 
-    img.save("decoded.png")?;
+//ANCHOR - Verify
+// Check a .ved file for damage, printing the damaged rows and every
+// repair decoding it would need. Fails when anything is wrong.
+fn verify(input: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let report = ved::verify(&read_input(input)?)?;
+    if !report.checksums {
+        eprintln!("ved: warning: the file has no checksums; only rows that fail to decode are found");
+    }
+    for rows in &report.rows {
+        if rows.len() == 1 {
+            println!("damaged row {}", rows.start);
+        } else {
+            println!("damaged rows {} to {}", rows.start, rows.end - 1);
+        }
+    }
+    // Damage no row is blamed for.
+    for repair in &report.repairs {
+        match repair {
+            ved::Repair::IgnoredTrailer { error } => println!("{}", error),
+            ved::Repair::DroppedRows { .. } => println!("{}", repair),
+            _ => {}
+        }
+    }
+    if !report.is_ok() {
+        return Err(format!("{} is damaged", input).into());
+    }
+    println!("ok");
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // encode()?;
-    decode()?;
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("ved: {}\n\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
 
-    Ok(())
+    let result = match command {
+        Command::Encode { input, output, options } => encode(&input, output, &options),
+        Command::Decode { input, output, options, region } => decode(&input, output, &options, region),
+        Command::Info { input } => info(&input),
+        Command::Verify { input } => verify(&input),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("ved: {}", error);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,260 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use image::error::{ LimitError, LimitErrorKind };
+use image::{ ImageDecoder, ImageError, ImageReader, ImageResult };
+use std::io::{ Cursor, Read, Write };
+use crate::checksum;
+
+//ANCHOR - Metadata
+/// An entry of the metadata of a binary .ved file: what image formats keep
+/// beside the pixels, and PNG in its ancillary chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Metadata {
+    /// A keyword and its text, such as "Author", "Copyright" or "Creation Time".
+    Text { key: String, value: String },
+    /// EXIF data, starting with its TIFF header.
+    Exif(Vec<u8>),
+    /// An XMP packet.
+    Xmp(String),
+    /// An ICC color profile.
+    Icc(Vec<u8>),
+}
+
+// Ids of the entry types in a binary file.
+const TEXT: u8 = 0;
+const EXIF: u8 = 1;
+const XMP: u8 = 2;
+const ICC: u8 = 3;
+
+impl Metadata {
+    /// Name of the entry type, as `ved info` prints it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metadata::Text { .. } => "text",
+            Metadata::Exif(_) => "exif",
+            Metadata::Xmp(_) => "xmp",
+            Metadata::Icc(_) => "icc",
+        }
+    }
+
+    // Type id, key and value of the entry as a binary file stores them.
+    pub(crate) fn parts(&self) -> (u8, &[u8], &[u8]) {
+        match self {
+            Metadata::Text { key, value } => (TEXT, key.as_bytes(), value.as_bytes()),
+            Metadata::Exif(exif) => (EXIF, &[], exif),
+            Metadata::Xmp(xmp) => (XMP, &[], xmp.as_bytes()),
+            Metadata::Icc(icc) => (ICC, &[], icc),
+        }
+    }
+
+    // The entry stored as these parts, or None for a type this version
+    // does not know.
+    pub(crate) fn from_parts(id: u8, key: &[u8], value: &[u8]) -> Result<Option<Metadata>, &'static str> {
+        let utf8 = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| "metadata text is not UTF-8");
+        Ok(Some(match id {
+            TEXT => Metadata::Text { key: utf8(key)?, value: utf8(value)? },
+            EXIF => Metadata::Exif(value.to_vec()),
+            XMP => Metadata::Xmp(utf8(value)?),
+            ICC => Metadata::Icc(value.to_vec()),
+            _ => return Ok(None),
+        }))
+    }
+}
+
+//ANCHOR - PNG
+const PNG_SIGNATURE: [u8; 8] = *b"\x89PNG\r\n\x1a\n";
+// Keyword of the iTXt chunk holding XMP.
+const XMP_KEY: &str = "XML:com.adobe.xmp";
+// Name given to the ICC profile of an iCCP chunk.
+const ICC_NAME: &str = "ICC profile";
+// Most bytes the compressed text and ICC profile chunks of a PNG may
+// inflate to, all together.
+const MAX_INFLATED: u64 = 1 << 26;
+
+/// Collect the metadata of an image file in any format `image` reads: its
+/// EXIF, XMP and ICC profile, and for PNG also its text chunks, in the
+/// order the file holds them. A PNG whose compressed chunks inflate to
+/// more than 64 MiB is rejected with a limit error.
+pub fn from_image(bytes: &[u8]) -> ImageResult<Vec<Metadata>> {
+    if bytes.starts_with(&PNG_SIGNATURE) {
+        let (mut budget, mut oversized) = (MAX_INFLATED, false);
+        let mut inflate = |data: &[u8]| {
+            let mut out = Vec::new();
+            ZlibDecoder::new(data).take(budget + 1).read_to_end(&mut out).ok()?;
+            match budget.checked_sub(out.len() as u64) {
+                Some(left) => budget = left,
+                None => oversized = true,
+            }
+            (!oversized).then_some(out)
+        };
+        let metadata = png_chunks(bytes).filter_map(|(kind, data)| from_png_chunk(kind, data, &mut inflate)).collect();
+        if oversized {
+            return Err(ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory)));
+        }
+        return Ok(metadata);
+    }
+    let mut decoder = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?.into_decoder()?;
+    let mut metadata = Vec::new();
+    if let Some(exif) = decoder.exif_metadata()? {
+        metadata.push(Metadata::Exif(exif));
+    }
+    if let Some(xmp) = decoder.xmp_metadata()?.and_then(|xmp| String::from_utf8(xmp).ok()) {
+        metadata.push(Metadata::Xmp(xmp));
+    }
+    if let Some(icc) = decoder.icc_profile()? {
+        metadata.push(Metadata::Icc(icc));
+    }
+    Ok(metadata)
+}
+
+/// Add the metadata to a PNG file as chunks right after its header: text
+/// as tEXt, or iTXt when it is not Latin-1, EXIF as eXIf, XMP as iTXt and
+/// the ICC profile as iCCP. PNG holds a single EXIF block, XMP packet and
+/// profile, so later ones are left out, as is text whose key is not a PNG
+/// keyword of 1 to 79 Latin-1 characters.
+pub fn add_to_png(png: &[u8], metadata: &[Metadata]) -> Vec<u8> {
+    // The header chunk comes first: its length, type, 13 bytes and CRC.
+    let header_end = (PNG_SIGNATURE.len() + 25).min(png.len());
+    let mut out = png[..header_end].to_vec();
+    let (mut exif, mut xmp, mut icc) = (false, false, false);
+    for entry in metadata {
+        match entry {
+            Metadata::Text { key, value } if is_keyword(key) => match latin1(value) {
+                Some(value) => write_chunk(&mut out, b"tEXt", &[&latin1(key).unwrap(), &[0], &value]),
+                None => write_chunk(&mut out, b"iTXt", &[key.as_bytes(), &[0, 0, 0, 0, 0], value.as_bytes()]),
+            },
+            Metadata::Text { .. } => {}
+            Metadata::Exif(data) if !exif => {
+                exif = true;
+                write_chunk(&mut out, b"eXIf", &[data]);
+            }
+            Metadata::Xmp(data) if !xmp => {
+                xmp = true;
+                write_chunk(&mut out, b"iTXt", &[XMP_KEY.as_bytes(), &[0, 0, 0, 0, 0], data.as_bytes()]);
+            }
+            Metadata::Icc(data) if !icc => {
+                icc = true;
+                let mut compressed = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                compressed.write_all(data).expect("writing to a Vec cannot fail");
+                let compressed = compressed.finish().expect("writing to a Vec cannot fail");
+                write_chunk(&mut out, b"iCCP", &[ICC_NAME.as_bytes(), &[0, 0], &compressed]);
+            }
+            _ => {}
+        }
+    }
+    out.extend_from_slice(&png[header_end..]);
+    out
+}
+
+// The type and data of each chunk of a PNG file, up to the first one that
+// does not fit.
+fn png_chunks(png: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = png.get(PNG_SIGNATURE.len()..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().unwrap()) as usize;
+        let kind = rest.get(4..8)?;
+        let data = rest.get(8..8 + len)?;
+        rest = rest.get(8 + len + 4..)?;
+        Some((kind, data))
+    })
+}
+
+// The metadata a PNG chunk holds, if any and if it can be read, with
+// `inflate` to decompress zlib data.
+fn from_png_chunk(
+    kind: &[u8],
+    data: &[u8],
+    inflate: &mut impl FnMut(&[u8]) -> Option<Vec<u8>>
+) -> Option<Metadata> {
+    // Most chunks start with a keyword ended by a zero byte.
+    let split = |data: &[u8]| {
+        let end = data.iter().position(|&byte| byte == 0)?;
+        Some((data[..end].to_vec(), data.get(end + 1..)?.to_vec()))
+    };
+    let from_latin1 = |bytes: &[u8]| bytes.iter().map(|&byte| char::from(byte)).collect::<String>();
+    match kind {
+        b"tEXt" => {
+            let (key, value) = split(data)?;
+            Some(Metadata::Text { key: from_latin1(&key), value: from_latin1(&value) })
+        }
+        b"zTXt" => {
+            let (key, value) = split(data)?;
+            // The compression method, 0 for zlib, comes first.
+            Some(Metadata::Text { key: from_latin1(&key), value: from_latin1(&inflate(value.get(1..)?)?) })
+        }
+        b"iTXt" => {
+            let (key, rest) = split(data)?;
+            let (compressed, rest) = (*rest.first()? != 0, rest.get(2..)?);
+            // Skip the language tag and translated keyword.
+            let (_, rest) = split(rest)?;
+            let (_, text) = split(&rest)?;
+            let text = String::from_utf8(if compressed { inflate(&text)? } else { text }).ok()?;
+            let key = String::from_utf8(key).ok()?;
+            Some(if key == XMP_KEY { Metadata::Xmp(text) } else { Metadata::Text { key, value: text } })
+        }
+        b"eXIf" => Some(Metadata::Exif(data.to_vec())),
+        b"iCCP" => {
+            let (_, profile) = split(data)?;
+            Some(Metadata::Icc(inflate(profile.get(1..)?)?))
+        }
+        _ => None,
+    }
+}
+
+// The Latin-1 bytes of a string, if every character has one.
+fn latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+// Whether a key can be a PNG keyword: 1 to 79 printable Latin-1 characters.
+fn is_keyword(key: &str) -> bool {
+    let printable = |c: char| matches!(c, ' '..='~' | '\u{A1}'..='\u{FF}');
+    (1..=79).contains(&key.chars().count()) && key.chars().all(printable)
+}
+
+// Append a PNG chunk made of these pieces of data.
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[&[u8]]) {
+    let start = out.len();
+    let len: usize = data.iter().map(|piece| piece.len()).sum();
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    for piece in data {
+        out.extend_from_slice(piece);
+    }
+    let crc = checksum::crc32(&out[start + 4..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ DynamicImage, ImageFormat, RgbImage };
+    use super::*;
+
+    // A 1x1 PNG with a zTXt chunk of `text`, compressed.
+    fn png_with_ztxt(text: &[u8]) -> Vec<u8> {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(1, 1)).write_to(&mut png, ImageFormat::Png).unwrap();
+        let png = png.into_inner();
+        let mut compressed = ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        compressed.write_all(text).unwrap();
+        let compressed = compressed.finish().unwrap();
+        let header_end = PNG_SIGNATURE.len() + 25;
+        let mut out = png[..header_end].to_vec();
+        write_chunk(&mut out, b"zTXt", &[b"Comment", &[0, 0], &compressed]);
+        out.extend_from_slice(&png[header_end..]);
+        out
+    }
+
+    #[test]
+    fn compressed_text_is_read() {
+        let metadata = from_image(&png_with_ztxt(b"hello")).unwrap();
+        assert_eq!(metadata, vec![Metadata::Text { key: "Comment".to_string(), value: "hello".to_string() }]);
+    }
+
+    #[test]
+    fn compressed_chunks_past_the_limit_are_rejected() {
+        let png = png_with_ztxt(&vec![b'a'; MAX_INFLATED as usize + 1]);
+        assert!(png.len() < 1 << 20);
+        assert!(matches!(from_image(&png), Err(ImageError::Limits(_))));
+    }
+}
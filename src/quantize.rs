@@ -0,0 +1,293 @@
+use image::{ DynamicImage, RgbaImage };
+use std::collections::HashMap;
+use rayon::prelude::*;
+
+/// Lossy reduction of an image to at most `colors` colors before encoding,
+/// so every pixel can be stored as a palette index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quantize {
+    pub method: QuantizeMethod,
+    pub colors: usize,
+    pub dither: Dither,
+}
+
+impl Default for Quantize {
+    fn default() -> Quantize {
+        Quantize { method: QuantizeMethod::default(), colors: 256, dither: Dither::default() }
+    }
+}
+
+/// How the reduced palette is chosen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuantizeMethod {
+    /// Repeatedly split the box of colors with the widest channel at its median.
+    #[default]
+    MedianCut,
+    /// Refine the median cut palette with k-means clustering.
+    KMeans,
+}
+
+/// How pixels are mapped onto the reduced palette.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Each pixel takes the nearest palette color.
+    #[default]
+    None,
+    /// The error of each pixel is pushed onto its unvisited neighbours.
+    FloydSteinberg,
+    /// Pixels are offset by a 4x4 Bayer matrix before taking the nearest color.
+    Ordered,
+}
+
+impl QuantizeMethod {
+    /// Look up a method by its command-line name.
+    pub fn from_name(name: &str) -> Option<QuantizeMethod> {
+        match name {
+            "median-cut" => Some(QuantizeMethod::MedianCut),
+            "k-means" => Some(QuantizeMethod::KMeans),
+            _ => None,
+        }
+    }
+}
+
+impl Dither {
+    /// Look up a dithering by its command-line name.
+    pub fn from_name(name: &str) -> Option<Dither> {
+        match name {
+            "none" => Some(Dither::None),
+            "floyd-steinberg" => Some(Dither::FloydSteinberg),
+            "ordered" => Some(Dither::Ordered),
+            _ => None,
+        }
+    }
+}
+
+// Maximum number of k-means refinement passes.
+const K_MEANS_PASSES: usize = 16;
+
+// 4x4 Bayer threshold matrix for ordered dithering.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+//ANCHOR - Quantize
+// Reduce an image to the requested number of colors. Images that already
+// fit are returned unchanged.
+pub(crate) fn quantize(img: &DynamicImage, options: &Quantize) -> RgbaImage {
+    let rgba = img.to_rgba8();
+    let histogram = histogram(&rgba);
+    let colors = options.colors.max(1);
+    if histogram.len() <= colors {
+        return rgba;
+    }
+
+    let palette = median_cut(&histogram, colors);
+    let palette = match options.method {
+        QuantizeMethod::MedianCut => palette,
+        QuantizeMethod::KMeans => k_means(&histogram, palette),
+    };
+    remap(&rgba, &palette, options.dither)
+}
+
+// Every color of the image with its pixel count, ordered by color.
+fn histogram(img: &RgbaImage) -> Vec<([u8; 4], u32)> {
+    let counts = img
+        .as_raw()
+        .par_chunks((img.width() as usize * 4).max(4))
+        .fold(HashMap::new, |mut counts: HashMap<[u8; 4], u32>, row| {
+            for pixel in row.chunks_exact(4) {
+                *counts.entry(pixel.try_into().unwrap()).or_insert(0) += 1;
+            }
+            counts
+        })
+        .reduce(HashMap::new, |mut total, counts| {
+            for (color, count) in counts {
+                *total.entry(color).or_insert(0) += count;
+            }
+            total
+        });
+    let mut histogram: Vec<([u8; 4], u32)> = counts.into_iter().collect();
+    histogram.sort_unstable();
+    histogram
+}
+
+// The channel with the largest spread among the colors, and that spread.
+fn widest_channel(colors: &[([u8; 4], u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let min = colors.iter().map(|(color, _)| color[channel]).min().unwrap_or(0);
+            let max = colors.iter().map(|(color, _)| color[channel]).max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|&(channel, spread)| (spread, std::cmp::Reverse(channel)))
+        .unwrap()
+}
+
+// The pixel-weighted mean of the colors.
+fn weighted_mean(colors: &[([u8; 4], u32)]) -> [u8; 4] {
+    let total: u64 = colors.iter().map(|&(_, count)| count as u64).sum();
+    let mut mean = [0; 4];
+    for (channel, value) in mean.iter_mut().enumerate() {
+        let sum: u64 = colors.iter().map(|&(color, count)| color[channel] as u64 * count as u64).sum();
+        *value = ((sum + total / 2) / total.max(1)) as u8;
+    }
+    mean
+}
+
+// Split the colors into boxes until there are `colors` of them, always
+// cutting the box with the widest channel at its pixel-weighted median.
+fn median_cut(histogram: &[([u8; 4], u32)], colors: usize) -> Vec<[u8; 4]> {
+    let mut entries = histogram.to_vec();
+    let mut boxes = Vec::new();
+    boxes.push(0..entries.len());
+    while boxes.len() < colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, range)| range.len() > 1)
+            .map(|(i, range)| (i, widest_channel(&entries[range.clone()])))
+            .max_by_key(|&(i, (_, spread))| (spread, std::cmp::Reverse(i)));
+        let Some((i, (channel, _))) = widest else {
+            break;
+        };
+
+        let range = boxes[i].clone();
+        let slice = &mut entries[range.clone()];
+        slice.sort_by_key(|&(color, _)| (color[channel], color));
+        let total: u64 = slice.iter().map(|&(_, count)| count as u64).sum();
+        let mut seen = 0;
+        let mut split = slice.len() / 2;
+        for (j, &(_, count)) in slice.iter().enumerate() {
+            seen += count as u64;
+            if seen * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        // Keep at least one color on each side of the cut.
+        let split = split.clamp(1, slice.len() - 1);
+        boxes[i] = range.start..range.start + split;
+        boxes.push(range.start + split..range.end);
+    }
+    boxes.into_iter().map(|range| weighted_mean(&entries[range])).collect()
+}
+
+// Squared distance between two colors over all four channels.
+fn distance(a: [u8; 4], b: [u8; 4]) -> u32 {
+    (0..4).map(|i| (a[i] as i32 - b[i] as i32).pow(2) as u32).sum()
+}
+
+// Index of the palette color closest to `color`.
+fn nearest(palette: &[[u8; 4]], color: [u8; 4]) -> usize {
+    (0..palette.len()).min_by_key(|&i| distance(palette[i], color)).unwrap()
+}
+
+// Move each palette color to the mean of the colors nearest to it until
+// nothing changes.
+fn k_means(histogram: &[([u8; 4], u32)], mut palette: Vec<[u8; 4]>) -> Vec<[u8; 4]> {
+    for _ in 0..K_MEANS_PASSES {
+        let sums = histogram
+            .par_iter()
+            .fold(
+                || vec![[0u64; 5]; palette.len()],
+                |mut sums, &(color, count)| {
+                    let sum = &mut sums[nearest(&palette, color)];
+                    for channel in 0..4 {
+                        sum[channel] += color[channel] as u64 * count as u64;
+                    }
+                    sum[4] += count as u64;
+                    sums
+                }
+            )
+            .reduce(
+                || vec![[0u64; 5]; palette.len()],
+                |mut total, sums| {
+                    for (total, sum) in total.iter_mut().zip(sums) {
+                        for channel in 0..5 {
+                            total[channel] += sum[channel];
+                        }
+                    }
+                    total
+                }
+            );
+
+        let mut changed = false;
+        for (center, sum) in palette.iter_mut().zip(sums) {
+            // A center nothing is close to stays where it is.
+            if sum[4] == 0 {
+                continue;
+            }
+            let mut mean = [0; 4];
+            for channel in 0..4 {
+                mean[channel] = ((sum[channel] + sum[4] / 2) / sum[4]) as u8;
+            }
+            changed |= mean != *center;
+            *center = mean;
+        }
+        if !changed {
+            break;
+        }
+    }
+    palette
+}
+
+// Map every pixel onto the palette.
+fn remap(img: &RgbaImage, palette: &[[u8; 4]], dither: Dither) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let mut out = img.clone();
+    let row_len = (width as usize * 4).max(4);
+
+    match dither {
+        Dither::None => {
+            out.par_chunks_mut(row_len).for_each(|row| {
+                let mut cache = HashMap::new();
+                for pixel in row.chunks_exact_mut(4) {
+                    let color: [u8; 4] = (&*pixel).try_into().unwrap();
+                    let index = *cache.entry(color).or_insert_with(|| nearest(palette, color));
+                    pixel.copy_from_slice(&palette[index]);
+                }
+            });
+        }
+        Dither::Ordered => {
+            // Spread the threshold over roughly one palette step per channel.
+            let spread = 255.0 / (palette.len() as f32).cbrt();
+            out.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let offset = ((BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5) * spread;
+                    let mut color: [u8; 4] = (&*pixel).try_into().unwrap();
+                    for value in &mut color[..3] {
+                        *value = (*value as f32 + offset).round().clamp(0.0, 255.0) as u8;
+                    }
+                    pixel.copy_from_slice(&palette[nearest(palette, color)]);
+                }
+            });
+        }
+        Dither::FloydSteinberg => {
+            // Accumulated error for the current and next row, with a pixel of
+            // padding on each side.
+            let padded = width as usize + 2;
+            let mut current = vec![[0f32; 3]; padded];
+            let mut next = vec![[0f32; 3]; padded];
+            for y in 0..height {
+                for x in 0..width {
+                    let pixel = out.get_pixel_mut(x, y);
+                    let i = x as usize + 1;
+                    let mut color = pixel.0;
+                    for channel in 0..3 {
+                        color[channel] = (color[channel] as f32 + current[i][channel]).round().clamp(0.0, 255.0) as u8;
+                    }
+                    let chosen = palette[nearest(palette, color)];
+                    for channel in 0..3 {
+                        let error = color[channel] as f32 - chosen[channel] as f32;
+                        current[i + 1][channel] += error * 7.0 / 16.0;
+                        next[i - 1][channel] += error * 3.0 / 16.0;
+                        next[i][channel] += error * 5.0 / 16.0;
+                        next[i + 1][channel] += error / 16.0;
+                    }
+                    pixel.0 = chosen;
+                }
+                std::mem::swap(&mut current, &mut next);
+                next.fill([0.0; 3]);
+            }
+        }
+    }
+    out
+}
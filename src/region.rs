@@ -0,0 +1,525 @@
+use image::{ DynamicImage, RgbaImage };
+use std::io::{ self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom };
+use crate::binary::{ self, BinaryHeader, Reader, BODY_OFFSET, MAGIC, RESTART_ROWS };
+use crate::compression::Compression;
+use crate::decode::{ self, DecodeLimits, DecodeOptions, Header, Palette, VedInfo, PALETTE_LINE };
+use crate::error::VedError;
+use crate::filter;
+use crate::history;
+use crate::sample::SampleType;
+use crate::stream::{ read_exact_or_eof, read_record };
+
+// Where the rows of an open file are read from.
+enum Data<R> {
+    File(R),
+    // A compressed file, inflated once since it cannot be seeked into.
+    Inflated(Cursor<Vec<u8>>),
+}
+
+impl<R: Read> Read for Data<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Data::File(reader) => reader.read(buf),
+            Data::Inflated(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: Seek> Seek for Data<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Data::File(reader) => reader.seek(pos),
+            Data::Inflated(reader) => reader.seek(pos),
+        }
+    }
+}
+
+// Per-container state of a RegionDecoder.
+enum Body {
+    Text { header: Header, variables: Palette },
+    Binary { header: BinaryHeader, palette: Vec<[u8; 4]> },
+}
+
+//ANCHOR - Region decoder
+/// Decodes rectangles out of a .ved file, reading only the rows they cover
+/// and expanding each row only up to the right edge of the rectangle.
+///
+/// Opening the file finds where every row starts: from the row index of a
+/// binary file written with `EncodeOptions::row_index`, by skipping from
+/// one length-prefixed row to the next in other binary files, or by
+/// scanning the lines of a text file. Rows outside a region are not
+/// checked, except for the rows it copies from or, in a filtered file, is
+/// predicted from, which are expanded as well. When runs span rows, the
+/// records of RESTART_ROWS rows covering the region are decoded whole.
+/// The checksums of the rows that are read are checked, but not the hash
+/// of the file, which would take reading all of it.
+pub struct RegionDecoder<R: Read + Seek> {
+    reader: BufReader<Data<R>>,
+    info: VedInfo,
+    body: Body,
+    // Where each row starts, followed by where the last one ends. Each
+    // record of rows counts as one row when runs span rows.
+    offsets: Vec<u64>,
+    // Never lenient, since rows outside a region are not checked.
+    options: DecodeOptions,
+}
+
+impl<R: Read + Seek> RegionDecoder<R> {
+    /// Read the header and palette and locate the rows. The file is read
+    /// from its start.
+    pub fn new(reader: R) -> Result<RegionDecoder<R>, VedError> {
+        RegionDecoder::with_limits(reader, DecodeLimits::default())
+    }
+
+    /// Like `new`, rejecting files that are larger than `limits` instead
+    /// of the default limits.
+    pub fn with_limits(reader: R, limits: DecodeLimits) -> Result<RegionDecoder<R>, VedError> {
+        let options = DecodeOptions { lenient: false, limits };
+        let mut reader = BufReader::new(Data::File(reader));
+        reader.rewind()?;
+        let mut prefix = Vec::with_capacity(MAGIC.len());
+        reader.by_ref().take(MAGIC.len() as u64).read_to_end(&mut prefix)?;
+        reader.rewind()?;
+        if prefix == MAGIC {
+            RegionDecoder::open_binary(reader, options)
+        } else {
+            RegionDecoder::open_text(reader, options)
+        }
+    }
+
+    /// The header of the file.
+    pub fn info(&self) -> &VedInfo {
+        &self.info
+    }
+
+    /// Decode the `width` by `height` pixels whose top left corner is at
+    /// (`x`, `y`), into the same image type as `decode_bytes` gives.
+    pub fn decode_region(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<DynamicImage, VedError> {
+        let fits = |start: u32, len: u32, max: u32| start.checked_add(len).is_some_and(|end| end <= max);
+        if !fits(x, width, self.info.width) || !fits(y, height, self.info.height) {
+            return Err(VedError::RegionOutOfBounds { x, y, width, height });
+        }
+        let (layout, sample) = (self.info.layout, self.info.sample);
+        if sample == SampleType::U8 {
+            return Ok(decode::into_image(self.decode_stored(x, y, width, height)?, layout, sample));
+        }
+
+        // Each byte plane of a row takes `info.width` stored pixels, so the
+        // region is cut out of each of them.
+        let planes = sample.bytes() as u32;
+        let span = if width == 0 { 0 } else { (planes - 1) * self.info.width + width };
+        let stored = self.decode_stored(x, y, span, height)?;
+        let mut img = RgbaImage::new(width * planes, height);
+        let (plane_len, width_len) = (self.info.width as usize * 4, width as usize * 4);
+        if width_len > 0 {
+            for (out, row) in img.chunks_exact_mut(width_len * planes as usize).zip(stored.chunks_exact(span as usize * 4)) {
+                for (plane, out) in out.chunks_exact_mut(width_len).enumerate() {
+                    out.copy_from_slice(&row[plane * plane_len..plane * plane_len + width_len]);
+                }
+            }
+        }
+        Ok(decode::into_image(img, layout, sample))
+    }
+
+    // Decode the `width` by `height` stored pixels whose top left corner is
+    // at (`x`, `y`), which have to be within the stored rows.
+    fn decode_stored(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<RgbaImage, VedError> {
+        let mut img = RgbaImage::new(width, height);
+        if width == 0 || height == 0 {
+            return Ok(img);
+        }
+        if matches!(&self.body, Body::Binary { header, .. } if header.spans_rows()) {
+            self.decode_records(x, y, &mut img)?;
+            return Ok(img);
+        }
+
+        // Rows that copy from the rows above them or are predicted from
+        // them need those rows too, expanded from the first column.
+        let (y, end) = (y as usize, (y + height) as usize);
+        let mut bytes = self.read_rows(y, end)?;
+        let mut top = y;
+        let mut expanded = matches!(&self.body, Body::Binary { header, .. } if header.is_filtered());
+        for row in y..end {
+            let record = self.record(&bytes, y, row);
+            if let Some(k) = self.depends_on(record, row)? {
+                top = top.min(k);
+                expanded = true;
+            }
+        }
+        let mut row = y;
+        while row > top {
+            row -= 1;
+            let record = self.read_rows(row, row + 1)?;
+            if let Some(k) = self.depends_on(&record, row)? {
+                top = top.min(k);
+            }
+        }
+        if top < y {
+            bytes = self.read_rows(top, end)?;
+        }
+
+        let first = if expanded { 0 } else { x as usize };
+        let row_len = (x + width) as usize * 4 - first * 4;
+        let mut pixels = vec![0; row_len * (end - top)];
+        let records: Vec<(usize, &[u8])> =
+            (top..end).map(|row| (self.offsets[row] as usize, self.record(&bytes, top, row))).collect();
+        match &self.body {
+            Body::Binary { header, palette } => {
+                let mut filters = Vec::new();
+                let mut rows = Vec::with_capacity(records.len());
+                for (row, &(offset, record)) in (top..).zip(&records) {
+                    let (offset, ops) = binary::split_record(header, row, record, offset)?;
+                    if header.is_filtered() {
+                        let (filter, offset, ops) = binary::split_filter(ops, offset)?;
+                        filters.push(filter);
+                        rows.push((offset, ops));
+                    } else {
+                        rows.push((offset, ops));
+                    }
+                }
+                history::expand_rows(
+                    &mut pixels,
+                    row_len,
+                    first,
+                    top,
+                    &rows,
+                    |row, &(_, ops)| binary::copied_row(ops, row, header).is_some(),
+                    |_, &(offset, ops), out| binary::decode_row(ops, offset, header, palette, out)
+                )?;
+                filter::unfilter_rows(&filters, &mut pixels, row_len, header.layout.filtered_channels());
+            }
+            Body::Text { header, variables } => {
+                let rows: Vec<&str> = records
+                    .iter()
+                    .zip(top..)
+                    .map(|(&(_, record), row)| {
+                        std::str::from_utf8(trim_line_ending(record)).map_err(|error| VedError::InvalidUtf8 {
+                            line: PALETTE_LINE + row + 1,
+                            column: error.valid_up_to() + 1,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                history::expand_rows(
+                    &mut pixels,
+                    row_len,
+                    first,
+                    top,
+                    &rows,
+                    |row, text| decode::copied_row(text, row).is_some(),
+                    |row, text, out| decode::decode_row(text, PALETTE_LINE + row + 1, header, variables, &self.options, out)
+                )?;
+            }
+        }
+
+        let skip = (x as usize - first) * 4;
+        for (out, row) in img.chunks_exact_mut(width as usize * 4).zip(pixels.chunks_exact(row_len).skip(y - top)) {
+            out.copy_from_slice(&row[skip..]);
+        }
+        Ok(img)
+    }
+
+    // Decode the records covering the region of `img` at (`x`, `y`) in a
+    // file whose runs span rows, and crop the region out of them.
+    fn decode_records(&mut self, x: u32, y: u32, img: &mut RgbaImage) -> Result<(), VedError> {
+        let Body::Binary { header, .. } = &self.body else { unreachable!() };
+        let header = header.clone();
+        let record_rows = RESTART_ROWS as usize;
+        let (y, end) = (y as usize, y as usize + img.height() as usize);
+        let last = (end - 1) / record_rows + 1;
+        // A filtered record may be predicted from the one above it.
+        let mut top = y / record_rows;
+        if header.is_filtered() {
+            while top > 0 {
+                let bytes = self.read_rows(top, top + 1)?;
+                let (offset, record) = binary::split_record(&header, top, &bytes, self.offsets[top] as usize)?;
+                if binary::split_filter(record, offset)?.0.is_standalone() {
+                    break;
+                }
+                top -= 1;
+            }
+        }
+        let bytes = self.read_rows(top, last)?;
+
+        let Body::Binary { header, palette } = &self.body else { unreachable!() };
+        let mut filters = Vec::new();
+        let mut records = Vec::with_capacity(last - top);
+        for record in top..last {
+            let bytes = self.record(&bytes, top, record);
+            let (offset, ops) = binary::split_record(header, record, bytes, self.offsets[record] as usize)?;
+            if header.is_filtered() {
+                let (record_filters, offset, ops) = binary::split_filters(ops, offset, header.record_rows(record))?;
+                filters.extend(record_filters);
+                records.push((offset, ops));
+            } else {
+                records.push((offset, ops));
+            }
+        }
+        let row_len = header.stored_width() as usize * 4;
+        let first_row = top * record_rows;
+        let rows = (last * record_rows).min(header.height as usize) - first_row;
+        let mut pixels = vec![0; row_len * rows];
+        binary::decode_strips(header, palette, &records, &mut pixels, false)?;
+        filter::unfilter_rows(&filters, &mut pixels, row_len, header.layout.filtered_channels());
+
+        let (skip, width) = (x as usize * 4, img.width() as usize * 4);
+        for (out, row) in img.chunks_exact_mut(width).zip(pixels.chunks_exact(row_len).skip(y - first_row)) {
+            out.copy_from_slice(&row[skip..skip + width]);
+        }
+        Ok(())
+    }
+
+    // The record of row `row` out of the bytes of the rows from `first` on.
+    fn record<'a>(&self, bytes: &'a [u8], first: usize, row: usize) -> &'a [u8] {
+        let start = self.offsets[first];
+        &bytes[(self.offsets[row] - start) as usize..(self.offsets[row + 1] - start) as usize]
+    }
+
+    // The earliest row that row `row` needs expanded before it: the first
+    // row it copies from, or the row above when its filter predicts from it.
+    fn depends_on(&self, record: &[u8], row: usize) -> Result<Option<usize>, VedError> {
+        match &self.body {
+            Body::Binary { header, .. } => {
+                let (offset, ops) = binary::split_record(header, row, record, self.offsets[row] as usize)?;
+                if !header.is_filtered() {
+                    return Ok(binary::copied_row(ops, row, header));
+                }
+                let (filter, _, ops) = binary::split_filter(ops, offset)?;
+                let above = row.checked_sub(1).filter(|_| !filter.is_standalone());
+                Ok(above.into_iter().chain(binary::copied_row(ops, row, header)).min())
+            }
+            Body::Text { .. } => {
+                let text = std::str::from_utf8(trim_line_ending(record)).ok();
+                Ok(text.and_then(|text| decode::copied_row(text, row)))
+            }
+        }
+    }
+
+    // Read the bytes of rows `first` up to `last`, which are contiguous.
+    fn read_rows(&mut self, first: usize, last: usize) -> Result<Vec<u8>, VedError> {
+        let start = self.offsets[first];
+        self.reader.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::new();
+        read_exact_or_eof(&mut self.reader, (self.offsets[last] - start) as usize, &mut bytes).map_err(
+            |error| match error {
+                VedError::UnexpectedEof { offset } => VedError::UnexpectedEof { offset: start as usize + offset },
+                error => error,
+            }
+        )?;
+        Ok(bytes)
+    }
+
+    fn open_binary(mut reader: BufReader<Data<R>>, options: DecodeOptions) -> Result<RegionDecoder<R>, VedError> {
+        let mut bytes = Vec::new();
+        read_exact_or_eof(&mut reader, BODY_OFFSET, &mut bytes)?;
+        let header = BinaryHeader::read(&mut (Reader { bytes: &bytes, pos: MAGIC.len() }))?;
+        let mut info = header.info();
+        options.limits.check(&info)?;
+        if header.compression() != Compression::None {
+            let mut file = Vec::new();
+            reader.rewind()?;
+            reader.read_to_end(&mut file)?;
+            let data = binary::uncompressed(&file, &options.limits, false)?.1.into_owned();
+            reader = BufReader::new(Data::Inflated(Cursor::new(data)));
+            reader.seek(SeekFrom::Start(BODY_OFFSET as u64))?;
+        }
+
+        let palette_len = (header.palette_len as usize).saturating_mul(header.layout.channels());
+        let mut palette_bytes = Vec::new();
+        read_exact_or_eof(&mut reader, palette_len, &mut palette_bytes).map_err(|error| match error {
+            VedError::UnexpectedEof { offset } => VedError::UnexpectedEof { offset: BODY_OFFSET + offset },
+            error => error,
+        })?;
+        let palette = binary::parse_palette(&palette_bytes, header.layout);
+        let mut rows_start = (BODY_OFFSET + palette_len) as u64;
+        if header.has_metadata() {
+            // Regions have no use for the metadata, so it is skipped unread.
+            rows_start = skip_record(&mut reader, rows_start)?;
+        }
+        if header.has_frames() {
+            // Only the number of frames is kept; regions are of the first.
+            let mut table = Vec::new();
+            let mut offset = rows_start as usize;
+            let start = read_record(&mut reader, &mut offset, &mut table, &options.limits)?;
+            info.frames = binary::parse_frames(&table, start)?.len();
+            rows_start = offset as u64;
+        }
+
+        let mut offsets = Vec::new();
+        if header.has_row_index() {
+            let index_start = reader.seek(SeekFrom::End(0))?.checked_sub(header.trailer_len());
+            let index_start = index_start
+                .filter(|&start| start >= rows_start)
+                .ok_or(VedError::UnexpectedEof { offset: rows_start as usize })?;
+            reader.seek(SeekFrom::Start(index_start))?;
+            let mut index = Vec::new();
+            read_exact_or_eof(&mut reader, header.row_index_len() as usize, &mut index)?;
+            offsets.extend(index.chunks_exact(8).map(|entry| u64::from_le_bytes(entry.try_into().unwrap())));
+            // The index of an animation ends with where the first frame
+            // does; otherwise the rows run up to the index.
+            if !header.has_frames() {
+                offsets.push(index_start);
+            }
+            let ordered = offsets.first().is_none_or(|&first| first == rows_start)
+                && offsets.windows(2).all(|pair| pair[0] <= pair[1])
+                && offsets.last().is_some_and(|&last| last <= index_start);
+            if !ordered {
+                return Err(VedError::BadBinary {
+                    offset: index_start as usize,
+                    message: "row index does not match the rows".to_string(),
+                });
+            }
+        } else {
+            // Hop from one row record to the next without decoding them.
+            let mut offset = rows_start;
+            for _ in 0..header.records() {
+                offsets.push(offset);
+                offset = skip_record(&mut reader, offset)?;
+            }
+            offsets.push(offset);
+        }
+
+        Ok(RegionDecoder { reader, info, body: Body::Binary { header, palette }, offsets, options })
+    }
+
+    fn open_text(mut reader: BufReader<Data<R>>, options: DecodeOptions) -> Result<RegionDecoder<R>, VedError> {
+        // The header and palette are parsed, the rows only located.
+        let header_line = read_text_line(&mut reader, decode::HEADER_LINE)?;
+        let header = decode::parse_header(header_line.as_deref())?;
+        let palette_line = read_text_line(&mut reader, PALETTE_LINE)?;
+        let variables = decode::parse_palette(palette_line.as_deref(), &header)?;
+        let info = header.info(variables.len());
+        options.limits.check(&info)?;
+
+        let mut offset = reader.stream_position()?;
+        let mut offsets = Vec::new();
+        loop {
+            let len = reader.skip_until(b'\n')? as u64;
+            if len == 0 {
+                break;
+            }
+            if offsets.len() == header.height as usize {
+                return Err(VedError::TooManyRows { line: PALETTE_LINE + offsets.len() + 1, expected: header.height });
+            }
+            offsets.push(offset);
+            offset += len;
+        }
+        if offsets.len() < header.height as usize {
+            return Err(VedError::TooFewRows {
+                line: PALETTE_LINE + offsets.len() + 1,
+                expected: header.height,
+                found: offsets.len() as u32,
+            });
+        }
+        offsets.push(offset);
+
+        Ok(RegionDecoder { reader, info, body: Body::Text { header, variables }, offsets, options })
+    }
+}
+
+// Skip the length-prefixed record at `offset`, where the reader is, without
+// reading it. Returns the offset of whatever follows it.
+fn skip_record<R: Read + Seek>(reader: &mut BufReader<R>, offset: u64) -> Result<u64, VedError> {
+    let mut consumed = 0;
+    let len = binary::read_varint(|| {
+        let mut byte = [0];
+        reader
+            .read_exact(&mut byte)
+            .map_err(|_| VedError::UnexpectedEof { offset: (offset + consumed) as usize })?;
+        consumed += 1;
+        Ok::<u8, VedError>(byte[0])
+    })?;
+    let len = len.ok_or_else(|| VedError::BadBinary {
+        offset: offset as usize,
+        message: "varint overflows 64 bits".to_string(),
+    })?;
+    let len = i64::try_from(len).map_err(|_| VedError::UnexpectedEof { offset: offset as usize })?;
+    reader.seek_relative(len)?;
+    Ok(offset + consumed + len as u64)
+}
+
+// Read the next line as text, or None at the end of the file.
+fn read_text_line<R: BufRead>(reader: &mut R, line: usize) -> Result<Option<String>, VedError> {
+    let mut bytes = Vec::new();
+    reader.read_until(b'\n', &mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let bytes = trim_line_ending(&bytes).to_vec();
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|error| VedError::InvalidUtf8 { line, column: error.utf8_error().valid_up_to() + 1 })
+}
+
+// A line without its "\n" or "\r\n".
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ imageops, ImageBuffer, Rgba };
+    use super::*;
+    use crate::decode::decode_bytes;
+    use crate::encode::{ encode_image_with, Container, EncodeOptions };
+    use crate::filter::{ Filtering, ANCHOR_ROWS };
+    use crate::history::ROW_WINDOW;
+
+    const WIDTH: u32 = 50;
+    const HEIGHT: u32 = 600;
+
+    // Rows with runs, rows that match the one above in places, and rows
+    // that repeat one from further up than an anchor or a record but
+    // within ROW_WINDOW.
+    fn image() -> DynamicImage {
+        let pixel = |x: u32, y: u32| {
+            let v = (x / 6 * 31 + y * 7) ^ (x % 3);
+            Rgba([v as u8, (v >> 3) as u8, (x * y) as u8, 255 - (y % 4) as u8])
+        };
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| match y % 11 {
+            3 if y >= 200 => pixel(x, y - 200),
+            5 | 6 => pixel(x, y - y % 11),
+            7 if x < 20 => pixel(x, y - 1),
+            _ => pixel(x, y),
+        }))
+    }
+
+    #[test]
+    fn regions_match_a_crop_of_the_whole_image() {
+        let (restart, anchor, window) = (RESTART_ROWS, ANCHOR_ROWS, ROW_WINDOW as u32);
+        let regions = [
+            (0, 0, WIDTH, HEIGHT),
+            (3, restart - 2, 20, 5),
+            (0, 2 * restart - 1, WIDTH, 2),
+            (7, anchor, 1, 1),
+            (10, anchor - 1, 30, anchor + 2),
+            (0, window - 3, WIDTH, 6),
+            (25, window + 3, 25, 1),
+            (5, HEIGHT - 70, 40, 70),
+            (WIDTH - 1, 413, 1, 122),
+        ];
+        let binary = EncodeOptions::default();
+        let indexed = EncodeOptions { row_index: true, ..binary.clone() };
+        let all_options = [
+            EncodeOptions { container: Container::Text, ..binary.clone() },
+            binary.clone(),
+            EncodeOptions { filtering: Filtering::Adaptive, ..binary.clone() },
+            EncodeOptions { compression: Compression::Deflate, checksums: true, ..binary.clone() },
+            indexed.clone(),
+            EncodeOptions { filtering: Filtering::Adaptive, ..indexed.clone() },
+            EncodeOptions { span_rows: true, ..indexed.clone() },
+            EncodeOptions { span_rows: true, filtering: Filtering::Adaptive, checksums: true, ..indexed },
+        ];
+        let img = image();
+        for options in all_options {
+            let bytes = encode_image_with(&img, &options);
+            let full = decode_bytes(&bytes).unwrap();
+            let mut decoder = RegionDecoder::new(Cursor::new(bytes)).unwrap();
+            for (x, y, width, height) in regions {
+                let region = decoder.decode_region(x, y, width, height).unwrap();
+                let crop = imageops::crop_imm(&full, x, y, width, height).to_image();
+                assert_eq!(region.to_rgba8(), crop, "region {:?} of {:?}", (x, y, width, height), options);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use image::ColorType;
+use rayon::prelude::*;
+
+/// The type of each channel sample of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleType {
+    #[default]
+    U8,
+    U16,
+    /// 32-bit IEEE 754 floats, stored by their bits.
+    F32,
+}
+
+impl SampleType {
+    /// Number of bytes of each sample.
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleType::U8 => 1,
+            SampleType::U16 => 2,
+            SampleType::F32 => 4,
+        }
+    }
+
+    /// Name of the sample type in the header of a text file and on the
+    /// command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleType::U8 => "u8",
+            SampleType::U16 => "u16",
+            SampleType::F32 => "f32",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SampleType> {
+        match name {
+            "u8" => Some(SampleType::U8),
+            "u16" => Some(SampleType::U16),
+            "f32" => Some(SampleType::F32),
+            _ => None,
+        }
+    }
+
+    // Id of the sample type in the flags of a binary header.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            SampleType::U8 => 0,
+            SampleType::U16 => 1,
+            SampleType::F32 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<SampleType> {
+        match id {
+            0 => Some(SampleType::U8),
+            1 => Some(SampleType::U16),
+            2 => Some(SampleType::F32),
+            _ => None,
+        }
+    }
+
+    // The sample type that holds the samples of an image exactly.
+    pub(crate) fn of(color: ColorType) -> SampleType {
+        match color {
+            ColorType::Rgb32F | ColorType::Rgba32F => SampleType::F32,
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => SampleType::U16,
+            _ => SampleType::U8,
+        }
+    }
+
+    // The native bytes of a fully opaque alpha sample.
+    fn opaque(self) -> [u8; 4] {
+        match self {
+            SampleType::U8 => [255, 0, 0, 0],
+            SampleType::U16 => {
+                let [a, b] = u16::MAX.to_ne_bytes();
+                [a, b, 0, 0]
+            }
+            SampleType::F32 => 1f32.to_ne_bytes(),
+        }
+    }
+}
+
+// Which byte of a sample in native byte order goes into byte plane
+// `plane`, the planes running from the most significant byte down.
+fn plane_byte(plane: usize, bytes: usize) -> usize {
+    if cfg!(target_endian = "little") { bytes - 1 - plane } else { plane }
+}
+
+// Split rows of `width` RGBA pixels of wider samples, in native byte
+// order, into byte planes: each row becomes a row of RGBA8 pixels holding
+// the most significant byte of every sample, followed by the next byte
+// down and so on, `width` pixels per plane.
+pub(crate) fn split_planes(samples: &[u8], width: usize, sample: SampleType) -> Vec<u8> {
+    let bytes = sample.bytes();
+    let row_len = width * 4 * bytes;
+    let mut planes = vec![0; samples.len()];
+    if row_len == 0 {
+        return planes;
+    }
+    planes.par_chunks_mut(row_len).zip(samples.par_chunks(row_len)).for_each(|(out, row)| {
+        for (i, value) in row.chunks_exact(bytes).enumerate() {
+            for plane in 0..bytes {
+                out[plane * width * 4 + i] = value[plane_byte(plane, bytes)];
+            }
+        }
+    });
+    planes
+}
+
+// Merge rows of byte planes back into RGBA samples in native byte order,
+// the reverse of `split_planes`. Alpha samples read as opaque unless the
+// file stores alpha.
+pub(crate) fn merge_planes(planes: &[u8], width: usize, sample: SampleType, alpha: bool, out: &mut [u8]) {
+    let bytes = sample.bytes();
+    let row_len = width * 4 * bytes;
+    if row_len == 0 {
+        return;
+    }
+    let opaque = sample.opaque();
+    out.par_chunks_mut(row_len).zip(planes.par_chunks(row_len)).for_each(|(out, row)| {
+        for (i, value) in out.chunks_exact_mut(bytes).enumerate() {
+            if !alpha && i % 4 == 3 {
+                value.copy_from_slice(&opaque[..bytes]);
+                continue;
+            }
+            for plane in 0..bytes {
+                value[plane_byte(plane, bytes)] = row[plane * width * 4 + i];
+            }
+        }
+    });
+}
@@ -0,0 +1,1118 @@
+use std::io::{ self, BufRead, Read, Write };
+use rayon::prelude::*;
+use crate::binary::{ self, BinaryHeader, ChannelLayout, Reader, Timing, MAGIC, RESTART_ROWS };
+use crate::checksum::{ Hashed, Xxh64 };
+use crate::compression::{ Sink, Source };
+use crate::decode::{ self, DecodeLimits, DecodeOptions, DecodeReport, Header, Palette, Repair, VedInfo };
+use crate::encode::{ self, ColorHasher, ColorMap, Container, Costs, EncodeOptions, Op };
+use crate::error::VedError;
+use crate::filter::{ self, Filter, Filtering, ANCHOR_ROWS };
+use crate::history::{ RowHistory, RowOut, ROW_WINDOW };
+use crate::metadata::Metadata;
+use crate::sample::{ self, SampleType };
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+//ANCHOR - Stream encoder
+/// Writes a .ved file row by row. The palette has to be known up front;
+/// only the rows passed to one `write_strip` call are held in memory.
+pub struct VedEncoder<W: Write> {
+    // Hashes everything written after the header when the file has checksums.
+    writer: Hashed<Sink<W>>,
+    container: Container,
+    // Pixels stored for each row: the width times the bytes per sample,
+    // since rows of wider samples are stored as byte planes.
+    width: u32,
+    height: u32,
+    sample: SampleType,
+    layout: ChannelLayout,
+    index_bits: u8,
+    variables: ColorMap<u32>,
+    costs: Costs,
+    rows_written: u32,
+    // Uncompressed offset of the next row and of every row written, when
+    // the file gets a row index.
+    offset: u64,
+    row_offsets: Option<Vec<u64>>,
+    // Predictors to apply, and the last row written for them to look at.
+    filtering: Filtering,
+    prev_row: Vec<u8>,
+    // The last rows as stored, and the latest row with each row hash, to
+    // find rows that repeat an earlier one.
+    history: RowHistory,
+    row_hashes: HashMap<u64, usize, BuildHasherDefault<ColorHasher>>,
+    // Whether runs span rows, and the rows and filters of the record that
+    // is not complete yet when they do.
+    spans_rows: bool,
+    pending: Vec<u8>,
+    pending_filters: Vec<Filter>,
+    // Whether each record starts with its checksum.
+    checksums: bool,
+    // Number of frames in the frame table, and of those written once the
+    // first is complete.
+    frames: usize,
+    frames_written: usize,
+}
+
+impl<W: Write> VedEncoder<W> {
+    /// Write the header and palette. Colors are RGBA; the alpha channel is
+    /// only stored when `alpha` is set. `options.color_mode` can store them
+    /// gray or as palette indices, in which case every pixel written has to
+    /// be in the palette. Without one, colors are stored as rgb or rgba.
+    /// With a wider `options.sample`, rows are given in that sample type
+    /// and the palette holds colors of their byte planes.
+    pub fn new(
+        writer: W,
+        width: u32,
+        height: u32,
+        alpha: bool,
+        palette: &[[u8; 4]],
+        options: &EncodeOptions
+    ) -> io::Result<VedEncoder<W>> {
+        VedEncoder::with_frames(writer, width, height, alpha, palette, options, &[])
+    }
+
+    // Like `new`, for an animation whose frames have these delays and
+    // disposals. The rows written make up the first frame, and
+    // `write_frame` writes each later one.
+    pub(crate) fn with_frames(
+        mut writer: W,
+        width: u32,
+        height: u32,
+        alpha: bool,
+        palette: &[[u8; 4]],
+        options: &EncodeOptions,
+        frames: &[Timing]
+    ) -> io::Result<VedEncoder<W>> {
+        let gray = palette.iter().all(|color| color[0] == color[1] && color[1] == color[2]);
+        let (layout, index_bits) = match options.color_mode {
+            Some(mode) => (mode.layout(gray, alpha), mode.index_bits().unwrap_or(0)),
+            None => (ChannelLayout::of(false, alpha), 0),
+        };
+        if index_bits > 0 && palette.len() > 1 << index_bits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} colors do not fit {}-bit indices", palette.len(), index_bits)
+            ));
+        }
+        let palette: Vec<[u8; 4]> = palette.iter().map(|&color| layout.load(&layout.store(color))).collect();
+        let sample = options.sample.unwrap_or_default();
+        let stored_width = width
+            .checked_mul(sample.bytes() as u32)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "image is too wide for its sample type"))?;
+        let row_index = options.row_index && options.container == Container::Binary;
+        let filtering = match options.container {
+            // Indices make no sense to predict.
+            Container::Binary if index_bits == 0 => options.filtering,
+            _ => Filtering::Off,
+        };
+        let mut flags = options.compression.id();
+        if row_index {
+            flags |= binary::FLAG_ROW_INDEX;
+        }
+        if filtering != Filtering::Off {
+            flags |= binary::FLAG_FILTERED;
+        }
+        let spans_rows = options.span_rows && options.container == Container::Binary;
+        if spans_rows {
+            flags |= binary::FLAG_SPANS_ROWS;
+        }
+        flags |= sample.id() << binary::SAMPLE_SHIFT;
+        let checksums = options.checksums && options.container == Container::Binary;
+        if checksums {
+            flags |= binary::FLAG_CHECKSUMS;
+        }
+        let mut offset = (binary::BODY_OFFSET + palette.len() * layout.channels()) as u64;
+        let writer = match options.container {
+            // Text files are never compressed.
+            Container::Text => {
+                encode::write_text_header(&mut writer, width, height, layout, index_bits, sample, &palette)?;
+                Hashed { inner: Sink::Plain(writer), hasher: None }
+            }
+            Container::Binary => {
+                let header = BinaryHeader {
+                    version: crate::FORMAT_VERSION as u8,
+                    width,
+                    height,
+                    layout,
+                    index_bits,
+                    flags,
+                    palette_len: palette.len() as u32,
+                };
+                let mut head = Vec::new();
+                binary::write_header(&mut head, &header)?;
+                writer.write_all(&head)?;
+                let hasher = checksums.then(|| {
+                    let mut hasher = Xxh64::new();
+                    hasher.update(&head);
+                    hasher
+                });
+                let mut writer = Hashed { inner: options.compression.writer(writer), hasher };
+                binary::write_palette(&mut writer, &palette, header.layout)?;
+                let metadata = binary::metadata_section(&options.metadata);
+                writer.write_all(&metadata)?;
+                let frame_table = binary::frame_table(frames);
+                writer.write_all(&frame_table)?;
+                offset += (metadata.len() + frame_table.len()) as u64;
+                writer
+            }
+        };
+        let variables = palette
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| (u32::from_be_bytes(color), i as u32))
+            .collect();
+        Ok(VedEncoder {
+            writer,
+            container: options.container,
+            width: stored_width,
+            height,
+            sample,
+            layout,
+            index_bits,
+            variables,
+            costs: Costs::new(
+                options.container,
+                layout,
+                index_bits,
+                stored_width as usize * if spans_rows { RESTART_ROWS as usize } else { 1 }
+            ),
+            rows_written: 0,
+            offset,
+            row_offsets: row_index.then(Vec::new),
+            filtering,
+            prev_row: vec![0; stored_width as usize * 4],
+            history: RowHistory::new(stored_width as usize * 4),
+            row_hashes: HashMap::default(),
+            spans_rows,
+            pending: Vec::new(),
+            pending_filters: Vec::new(),
+            checksums,
+            frames: frames.len().max(1),
+            frames_written: 0,
+        })
+    }
+
+    /// Write one row of `width` RGBA pixels, whose samples are bytes or, for
+    /// a wider `EncodeOptions::sample`, in native byte order.
+    pub fn write_row(&mut self, row: &[u8]) -> io::Result<()> {
+        self.write_strip(row)
+    }
+
+    /// Write several consecutive rows of RGBA pixels, encoding them in parallel.
+    pub fn write_strip(&mut self, rows: &[u8]) -> io::Result<()> {
+        let row_len = self.width as usize * 4;
+        // Rows of a zero-width image hold no data and are written by `finish`.
+        if !self.check_strip(rows)? {
+            return Ok(());
+        }
+        let planes;
+        let rows = if self.sample == SampleType::U8 {
+            rows
+        } else {
+            let width = self.width as usize / self.sample.bytes();
+            planes = sample::split_planes(rows, width, self.sample);
+            &planes[..]
+        };
+        if self.filtering == Filtering::Off {
+            return self.write_filtered(rows, &[]);
+        }
+
+        let prev = (self.rows_written > 0).then_some(&self.prev_row[..]);
+        let channels = self.layout.filtered_channels();
+        let anchors = self.row_offsets.is_some();
+        let (filters, residuals) =
+            filter::filter_rows(self.filtering, rows, prev, row_len, self.rows_written, channels, anchors);
+        self.prev_row.copy_from_slice(&rows[rows.len() - row_len..]);
+        self.write_filtered(&residuals, &filters)
+    }
+
+    // Write rows that were already filtered, with the filter of each row,
+    // or rows of an unfiltered file with no filters. Rows of wider samples
+    // are given as byte planes.
+    pub(crate) fn write_filtered(&mut self, rows: &[u8], filters: &[Filter]) -> io::Result<()> {
+        if !self.check_strip(rows)? {
+            return Ok(());
+        }
+        if self.index_bits > 0 {
+            let layout = self.layout;
+            if !rows.par_chunks_exact(4).all(|pixel| self.variables.contains_key(&layout.normalize(encode::pack(pixel)))) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a pixel is not in the palette of an indexed file"));
+            }
+        }
+        let row_len = self.width as usize * 4;
+        let count = rows.len() / row_len;
+        if self.spans_rows {
+            self.pending.extend_from_slice(rows);
+            self.pending_filters.extend_from_slice(filters);
+            self.rows_written += count as u32;
+            return self.write_records(false);
+        }
+        let first_row = self.rows_written as usize;
+        let anchors = self.row_offsets.is_some();
+        // The row above the strip, before the history moves past it.
+        let above_strip = self.history.above().previous().map(<[u8]>::to_vec);
+        let same_rows = self.match_rows(rows);
+        let encoded_rows: Vec<Vec<u8>> = rows
+            .par_chunks(row_len)
+            .enumerate()
+            .map_init(Vec::new, |ops, (i, row)| {
+                let y = first_row + i;
+                let above = match i {
+                    0 => above_strip.as_deref(),
+                    _ => Some(&rows[(i - 1) * row_len..i * row_len]),
+                };
+                // Anchor rows stand on their own, like their filters.
+                let above = above.filter(|_| !(anchors && (y as u32).is_multiple_of(ANCHOR_ROWS)));
+                encode::encode_row(row, above, self.width as usize, &self.variables, &self.costs, ops);
+                let filter = filters.get(i..=i).unwrap_or_default();
+                let mut out = Vec::new();
+                write_ops(self.container, self.layout, self.index_bits, ops, filter, self.checksums, &mut out);
+                if let Some(k) = same_rows[i] {
+                    let mut same = Vec::new();
+                    let row = [Op::Row(k as u64)];
+                    write_ops(self.container, self.layout, self.index_bits, &row, filter, self.checksums, &mut same);
+                    if same.len() < out.len() {
+                        out = same;
+                    }
+                }
+                out
+            })
+            .collect();
+        for row in encoded_rows {
+            self.push_row_offset(row.len());
+            self.writer.write_all(&row)?;
+        }
+        self.rows_written += count as u32;
+        Ok(())
+    }
+
+    // Encode the complete records of pending rows in parallel, each as one
+    // run of pixels, and write them, along with the incomplete last one
+    // when `all` is set.
+    fn write_records(&mut self, all: bool) -> io::Result<()> {
+        let row_len = self.width as usize * 4;
+        let rows = self.pending.len() / row_len;
+        let record_rows = RESTART_ROWS as usize;
+        let count = if all { rows.div_ceil(record_rows) } else { rows / record_rows };
+        let records: Vec<Vec<u8>> = (0..count)
+            .into_par_iter()
+            .map_init(Vec::new, |ops, i| {
+                let record = i * record_rows..((i + 1) * record_rows).min(rows);
+                let pixels = &self.pending[record.start * row_len..record.end * row_len];
+                encode::encode_row(pixels, None, self.width as usize, &self.variables, &self.costs, ops);
+                let filters = self.pending_filters.get(record).unwrap_or_default();
+                let mut out = Vec::new();
+                write_ops(self.container, self.layout, self.index_bits, ops, filters, self.checksums, &mut out);
+                out
+            })
+            .collect();
+        for record in records {
+            self.push_row_offset(record.len());
+            self.writer.write_all(&record)?;
+        }
+        let written = (count * record_rows).min(rows);
+        self.pending.drain(..written * row_len);
+        self.pending_filters.drain(..written.min(self.pending_filters.len()));
+        Ok(())
+    }
+
+    // For each row of a strip, an earlier row within reach that it is the
+    // same as. Every row is added to the history on the way.
+    fn match_rows(&mut self, rows: &[u8]) -> Vec<Option<usize>> {
+        let row_len = self.width as usize * 4;
+        let hashes: Vec<u64> = rows.par_chunks(row_len).map(encode::hash_row).collect();
+        let anchors = self.row_offsets.is_some();
+        let first_row = self.rows_written as usize;
+        rows.chunks(row_len)
+            .zip(hashes)
+            .enumerate()
+            .map(|(i, (row, hash))| {
+                let y = first_row + i;
+                let block = if anchors { y - y % ANCHOR_ROWS as usize } else { 0 };
+                let oldest = y.saturating_sub(ROW_WINDOW).max(block);
+                let same = self.row_hashes
+                    .get(&hash)
+                    .copied()
+                    .filter(|&k| k >= oldest && self.history.above().get(k) == Some(row));
+                self.history.push(row);
+                self.row_hashes.insert(hash, y);
+                same
+            })
+            .collect()
+    }
+
+    // Write a frame after the first as the rows where it differs from
+    // `reference`, the picture the frame before leaves, copying what they
+    // share with it. Both are RGBA pixels of the stored width.
+    pub(crate) fn write_frame(&mut self, frame: &[u8], reference: &[u8]) -> io::Result<()> {
+        self.finish_first_frame()?;
+        let row_len = self.width as usize * 4;
+        if self.frames_written == self.frames {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "more frames than the frame table holds"));
+        }
+        if frame.len() != row_len * self.height as usize || reference.len() != frame.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame does not match the image dimensions"));
+        }
+        if self.index_bits > 0 {
+            let layout = self.layout;
+            if !frame.par_chunks_exact(4).all(|pixel| self.variables.contains_key(&layout.normalize(encode::pack(pixel)))) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a pixel is not in the palette of an indexed file"));
+            }
+        }
+        let changed: Vec<usize> = match row_len {
+            0 => Vec::new(),
+            _ => (0..self.height as usize)
+                .into_par_iter()
+                .filter(|&y| frame[y * row_len..(y + 1) * row_len] != reference[y * row_len..(y + 1) * row_len])
+                .collect(),
+        };
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        let mut end = 0;
+        for &y in &changed {
+            match runs.last_mut() {
+                Some((_, count)) if y == end => *count += 1,
+                _ => runs.push(((y - end) as u32, 1)),
+            }
+            end = y + 1;
+        }
+        let records: Vec<Vec<u8>> = changed
+            .par_iter()
+            .map_init(Vec::new, |ops, &y| {
+                let rows = y * row_len..(y + 1) * row_len;
+                encode::encode_row(&frame[rows.clone()], Some(&reference[rows]), self.width as usize, &self.variables, &self.costs, ops);
+                let mut out = Vec::new();
+                binary::write_row(ops, self.layout, self.index_bits, &[], self.checksums, &mut out);
+                out
+            })
+            .collect();
+        let mut out = Vec::new();
+        binary::write_frame(&runs, &records, &mut out);
+        self.offset += out.len() as u64;
+        self.writer.write_all(&out)?;
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    // Write what is left of the first frame once all of its rows are in:
+    // the rows of a zero-width image, or the last record when runs span
+    // rows. The row index then notes where the first frame ends.
+    fn finish_first_frame(&mut self) -> io::Result<()> {
+        if self.frames_written > 0 {
+            return Ok(());
+        }
+        if self.width == 0 {
+            // Each record is one row, or RESTART_ROWS of them when runs span rows.
+            let record_rows = if self.spans_rows { RESTART_ROWS } else { 1 };
+            let mut out = Vec::new();
+            for record in 0..self.height.div_ceil(record_rows) {
+                let start = out.len();
+                match self.container {
+                    Container::Text => encode::write_text_row(&[], self.layout, &mut out),
+                    Container::Binary => {
+                        let rows = record_rows.min(self.height - record * record_rows) as usize;
+                        let filters = match self.filtering {
+                            Filtering::Off => Vec::new(),
+                            _ => vec![Filter::None; rows],
+                        };
+                        binary::write_row(&[], self.layout, self.index_bits, &filters, self.checksums, &mut out)
+                    }
+                }
+                self.push_row_offset(out.len() - start);
+            }
+            self.writer.write_all(&out)?;
+            self.rows_written = self.height;
+        } else if self.spans_rows {
+            self.write_records(true)?;
+        }
+        if self.rows_written != self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} of {} rows were written", self.rows_written, self.height)
+            ));
+        }
+        if let Some(offsets) = &mut self.row_offsets {
+            offsets.push(self.offset);
+        }
+        self.frames_written = 1;
+        Ok(())
+    }
+
+    /// Check that every row was written, flush and hand back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_first_frame()?;
+        if self.frames_written != self.frames {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} of {} frames were written", self.frames_written, self.frames)
+            ));
+        }
+        if let Some(offsets) = &self.row_offsets {
+            binary::write_row_index(&mut self.writer, offsets)?;
+        }
+        if let Some(hasher) = self.writer.hasher.take() {
+            self.writer.write_all(&hasher.finish().to_le_bytes())?;
+        }
+        let mut writer = self.writer.inner.finish()?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    // Check that a strip holds whole rows that fit in the image. Returns
+    // false for a zero-width image, whose rows are written by `finish`.
+    fn check_strip(&self, rows: &[u8]) -> io::Result<bool> {
+        let row_len = self.width as usize * 4;
+        if row_len == 0 {
+            return Ok(false);
+        }
+        let count = rows.len() / row_len;
+        if !rows.len().is_multiple_of(row_len) || (self.rows_written as usize) + count > (self.height as usize) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "rows do not fit the image dimensions"));
+        }
+        Ok(true)
+    }
+
+    // Note where a row of `len` bytes starts, for the row index.
+    fn push_row_offset(&mut self, len: usize) {
+        if let Some(offsets) = &mut self.row_offsets {
+            offsets.push(self.offset);
+        }
+        self.offset += len as u64;
+    }
+}
+
+// Append the ops of one row, or of a record of rows with the filter of
+// each, in the format of the container, with a checksum if `checksum` is
+// set in a binary file.
+fn write_ops(
+    container: Container,
+    layout: ChannelLayout,
+    index_bits: u8,
+    ops: &[Op],
+    filters: &[Filter],
+    checksum: bool,
+    out: &mut Vec<u8>
+) {
+    match container {
+        Container::Text => encode::write_text_row(ops, layout, out),
+        Container::Binary => binary::write_row(ops, layout, index_bits, filters, checksum, out),
+    }
+}
+
+//ANCHOR - Stream decoder
+// Per-container state of a VedDecoder.
+enum Body {
+    Text {
+        header: Header,
+        variables: Palette,
+        // Whether a lenient decode already ran out of rows.
+        ended: bool,
+    },
+    Binary {
+        header: BinaryHeader,
+        palette: Vec<[u8; 4]>,
+        // Byte offset of the next row in the file.
+        offset: usize,
+        row: Vec<u8>,
+        // Where each row read so far starts, to check the row index against.
+        offsets: Vec<u64>,
+        // Number of frames; those after the first are checked, not decoded.
+        frames: usize,
+        // The last row read, which filtered rows are predicted from.
+        prev: Vec<u8>,
+        // The rows of the current record and their filters, when runs span rows.
+        strip: Vec<u8>,
+        filters: Vec<Filter>,
+        // For a lenient decode: whether each row read so far is damaged,
+        // whether the current record is, and whether the file ran out of rows.
+        damaged: Vec<bool>,
+        damaged_record: bool,
+        ended: bool,
+    },
+}
+
+/// Reads a .ved file of either container row by row, holding one row and
+/// the palette in memory, or one record of rows when runs span rows.
+pub struct VedDecoder<R: BufRead> {
+    // Hashes everything read when the file has checksums.
+    reader: Hashed<Source<R>>,
+    // Bytes read while sniffing the container that belong to the first line.
+    pending: Vec<u8>,
+    // Number of text lines read so far.
+    line: usize,
+    body: Body,
+    info: VedInfo,
+    metadata: Vec<Metadata>,
+    options: DecodeOptions,
+    report: DecodeReport,
+    rows_read: u32,
+    // The last rows as stored, for rows that copy from them.
+    history: RowHistory,
+    // The byte planes of the current row, for wider samples.
+    planes: Vec<u8>,
+}
+
+impl<R: BufRead> VedDecoder<R> {
+    /// Read the header, palette and metadata.
+    pub fn new(mut reader: R, options: &DecodeOptions) -> Result<VedDecoder<R>, VedError> {
+        let mut prefix = Vec::with_capacity(MAGIC.len());
+        reader.by_ref().take(MAGIC.len() as u64).read_to_end(&mut prefix)?;
+
+        if prefix == MAGIC {
+            let mut bytes = prefix;
+            read_exact_or_eof(&mut reader, BinaryHeader::SIZE, &mut bytes)?;
+            let header = BinaryHeader::read(&mut (Reader { bytes: &bytes, pos: MAGIC.len() }))?;
+            options.limits.check(&header.info())?;
+            let hasher = header.has_checksums().then(|| {
+                let mut hasher = Xxh64::new();
+                hasher.update(&bytes);
+                hasher
+            });
+            let mut reader = Hashed { inner: header.compression().reader(reader), hasher };
+            let palette_start = bytes.len();
+            let palette_len = (header.palette_len as usize).saturating_mul(header.layout.channels());
+            read_exact_or_eof(&mut reader, palette_len, &mut bytes)?;
+            let palette = binary::parse_palette(&bytes[palette_start..], header.layout);
+            let mut offset = bytes.len();
+            let mut metadata = Vec::new();
+            if header.has_metadata() {
+                let mut entries = Vec::new();
+                let start = read_record(&mut reader, &mut offset, &mut entries, &options.limits)?;
+                metadata = binary::parse_metadata(&entries, start)?;
+            }
+            let mut info = header.info();
+            if header.has_frames() {
+                let mut table = Vec::new();
+                let start = read_record(&mut reader, &mut offset, &mut table, &options.limits)?;
+                info.frames = binary::parse_frames(&table, start)?.len();
+            }
+            let row_len = header.stored_width() as usize * 4;
+            let body = Body::Binary {
+                prev: if header.is_filtered() { vec![0; row_len] } else { Vec::new() },
+                header,
+                palette,
+                offset,
+                row: Vec::new(),
+                offsets: Vec::new(),
+                frames: info.frames,
+                strip: Vec::new(),
+                filters: Vec::new(),
+                damaged: Vec::new(),
+                damaged_record: false,
+                ended: false,
+            };
+            return Ok(VedDecoder {
+                reader,
+                pending: Vec::new(),
+                line: 0,
+                body,
+                info,
+                metadata,
+                options: options.clone(),
+                report: DecodeReport::default(),
+                rows_read: 0,
+                history: RowHistory::new(row_len),
+                planes: Vec::new(),
+            });
+        }
+
+        // Placeholders until the header and palette lines are read.
+        let header = Header {
+            version: 0,
+            width: 0,
+            height: 0,
+            layout: ChannelLayout::Rgb,
+            index_bits: 0,
+            sample: SampleType::U8,
+        };
+        let mut decoder = VedDecoder {
+            reader: Hashed { inner: Source::Plain(reader), hasher: None },
+            pending: prefix,
+            line: 0,
+            info: header.info(0),
+            metadata: Vec::new(),
+            body: Body::Text { header, variables: Palette::default(), ended: false },
+            options: options.clone(),
+            report: DecodeReport::default(),
+            rows_read: 0,
+            history: RowHistory::new(0),
+            planes: Vec::new(),
+        };
+        let header = decode::parse_header(decoder.next_line()?.as_deref())?;
+        let variables = decode::parse_palette(decoder.next_line()?.as_deref(), &header)?;
+        options.limits.check(&header.info(variables.len()))?;
+        decoder.history = RowHistory::new(header.stored_width() as usize * 4);
+        decoder.info = header.info(variables.len());
+        decoder.body = Body::Text { header, variables, ended: false };
+        Ok(decoder)
+    }
+
+    /// The header of the file. `rows` is the height given in the header.
+    pub fn info(&self) -> &VedInfo {
+        &self.info
+    }
+
+    /// The text, EXIF, XMP and ICC profiles stored with the image.
+    pub fn metadata(&self) -> &[Metadata] {
+        &self.metadata
+    }
+
+    /// Repairs made so far by lenient decoding.
+    pub fn report(&self) -> &DecodeReport {
+        &self.report
+    }
+
+    /// Decode the next row into `row`, which must hold `width` RGBA pixels
+    /// of the sample type in `info().sample`, each sample in native byte
+    /// order. Returns false once every row has been read.
+    pub fn read_row(&mut self, row: &mut [u8]) -> Result<bool, VedError> {
+        assert_eq!(row.len(), self.row_len(), "row buffer does not match the image width");
+        if self.rows_read == self.info.height {
+            return Ok(false);
+        }
+
+        let sample = self.info.sample;
+        if sample == SampleType::U8 {
+            self.read_stored_row(row)?;
+        } else {
+            let mut planes = std::mem::take(&mut self.planes);
+            planes.resize(row.len(), 0);
+            self.read_stored_row(&mut planes)?;
+            sample::merge_planes(&planes, self.info.width as usize, sample, self.info.layout.has_alpha(), row);
+            self.planes = planes;
+        }
+        self.rows_read += 1;
+        Ok(true)
+    }
+
+    // Bytes of each row handed out by `read_row`.
+    fn row_len(&self) -> usize {
+        self.info.width as usize * 4 * self.info.sample.bytes()
+    }
+
+    // Decode the next row as stored, in byte planes for wider samples.
+    fn read_stored_row(&mut self, out: &mut [u8]) -> Result<(), VedError> {
+        match &self.body {
+            Body::Text { .. } => self.read_text_row(out),
+            Body::Binary { .. } => self.read_binary_row(out),
+        }
+    }
+
+    /// Check that nothing follows the last row and return the report of
+    /// everything lenient decoding repaired. Unread rows are decoded and
+    /// discarded first.
+    pub fn finish(mut self) -> Result<DecodeReport, VedError> {
+        let mut scratch = vec![0; self.row_len()];
+        while self.read_row(&mut scratch)? {}
+
+        match self.body {
+            Body::Text { .. } => {
+                let first_extra_line = self.line + 1;
+                let mut count = 0;
+                while self.next_line()?.is_some() {
+                    count += 1;
+                }
+                if count > 0 {
+                    if !self.options.lenient {
+                        return Err(VedError::TooManyRows { line: first_extra_line, expected: self.info.height });
+                    }
+                    self.report.repairs.push(Repair::DroppedRows { line: first_extra_line, count });
+                }
+            }
+            // Nothing follows the rows of a file that ended early.
+            Body::Binary { ended: true, .. } => {}
+            Body::Binary { header, offset, offsets, frames, .. } => {
+                let checked = check_trailer(&mut self.reader, &header, offset, offsets, frames, &self.options.limits);
+                if let Err(error) = checked {
+                    if !self.options.lenient {
+                        return Err(error);
+                    }
+                    self.report.repairs.push(Repair::IgnoredTrailer { error });
+                }
+            }
+        }
+        Ok(self.report)
+    }
+
+    // Read the next line without its line ending, or None at the end of the file.
+    fn next_line(&mut self) -> Result<Option<String>, VedError> {
+        let mut line = std::mem::take(&mut self.pending);
+        match line.iter().position(|&b| b == b'\n') {
+            Some(end) => self.pending = line.split_off(end + 1),
+            None => {
+                self.reader.read_until(b'\n', &mut line)?;
+            }
+        }
+        if line.is_empty() {
+            return Ok(None);
+        }
+        self.line += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        String::from_utf8(line).map(Some).map_err(|error| VedError::InvalidUtf8 {
+            line: self.line,
+            column: error.utf8_error().valid_up_to() + 1,
+        })
+    }
+
+    // Decode the next text row into `out`; a row missing from a lenient file
+    // is transparent black.
+    fn read_text_row(&mut self, out: &mut [u8]) -> Result<(), VedError> {
+        let text = self.next_line()?;
+        let Body::Text { header, variables, ended } = &mut self.body else { unreachable!() };
+        match text {
+            Some(text) => {
+                let out = RowOut { first: 0, pixels: out, above: self.history.above() };
+                let repair = decode::decode_row(&text, self.line, header, variables, &self.options, out)?;
+                self.report.repairs.extend(repair);
+            }
+            None if self.options.lenient => {
+                if !*ended {
+                    *ended = true;
+                    self.report.repairs.push(Repair::MissingRows { expected: self.info.height, found: self.rows_read });
+                }
+                out.fill(0);
+            }
+            None => {
+                return Err(VedError::TooFewRows {
+                    line: self.line + 1,
+                    expected: self.info.height,
+                    found: self.rows_read,
+                });
+            }
+        }
+        self.history.push(out);
+        Ok(())
+    }
+
+    // Decode the next binary row into `out`. A lenient decode leaves rows
+    // it cannot decode and the rows that depend on them transparent black,
+    // like the rows missing from a file that ends early.
+    fn read_binary_row(&mut self, out: &mut [u8]) -> Result<(), VedError> {
+        let lenient = self.options.lenient;
+        let y = self.rows_read as usize;
+        let Body::Binary { header, palette, offset, row, offsets, prev, strip, filters, damaged, damaged_record, ended, .. } =
+            &mut self.body
+        else {
+            unreachable!()
+        };
+        if *ended {
+            out.fill(0);
+            return Ok(());
+        }
+        let channels = header.layout.filtered_channels();
+        let in_record = if header.spans_rows() { y % RESTART_ROWS as usize } else { 0 };
+        if in_record == 0 {
+            if header.has_row_index() {
+                offsets.push(*offset as u64);
+            }
+            match read_record(&mut self.reader, offset, row, &self.options.limits) {
+                Ok(start) => {
+                    let record = if header.spans_rows() { y / RESTART_ROWS as usize } else { y };
+                    let rows = header.record_rows(record);
+                    let decoded = binary::check_record(header, record, row, start).and_then(|(start, contents)| {
+                        let (record_filters, ops_offset, ops) = match header.is_filtered() {
+                            true => binary::split_filters(contents, start, rows)?,
+                            false => (Vec::new(), start, contents),
+                        };
+                        *filters = record_filters;
+                        if header.spans_rows() {
+                            strip.resize(rows * out.len(), 0);
+                            return binary::decode_strip(ops, ops_offset, header, palette, strip).map(|()| None);
+                        }
+                        let out = RowOut { first: 0, pixels: &mut *out, above: self.history.above() };
+                        binary::decode_row(ops, ops_offset, header, palette, out)?;
+                        Ok(binary::copied_row(ops, y, header))
+                    });
+                    *damaged_record = match decoded {
+                        Ok(copied) => copied.is_some_and(|k| damaged.get(k) == Some(&true)),
+                        Err(error) if !lenient => return Err(error),
+                        Err(_) => {
+                            filters.clear();
+                            filters.resize(if header.is_filtered() { rows } else { 0 }, Filter::None);
+                            strip.resize(rows * out.len(), 0);
+                            strip.fill(0);
+                            true
+                        }
+                    };
+                }
+                Err(error) if !lenient => return Err(error),
+                Err(_) => {
+                    *ended = true;
+                    self.report.repairs.push(Repair::MissingRows { expected: header.height, found: y as u32 });
+                    out.fill(0);
+                    return Ok(());
+                }
+            }
+        }
+
+        if header.spans_rows() {
+            out.copy_from_slice(&strip[in_record * out.len()..(in_record + 1) * out.len()]);
+        } else if *damaged_record {
+            out.fill(0);
+        }
+        if !header.spans_rows() {
+            self.history.push(out);
+        }
+        let filter = filters.get(in_record).copied();
+        if let Some(filter) = filter {
+            let above = (y > 0).then_some(&prev[..]);
+            filter::unfilter_row(filter, out, above, channels);
+        }
+        // A row predicted from a damaged row is damaged too.
+        let predicted = filter.is_some_and(|filter| !filter.is_standalone()) && damaged.last() == Some(&true);
+        if *damaged_record || predicted {
+            out.fill(0);
+            match self.report.repairs.last_mut() {
+                Some(Repair::DamagedRows { first, count }) if (*first + *count) as usize == y => *count += 1,
+                _ => self.report.repairs.push(Repair::DamagedRows { first: y as u32, count: 1 }),
+            }
+        }
+        damaged.push(*damaged_record || predicted);
+        if header.is_filtered() {
+            prev.copy_from_slice(out);
+        }
+        Ok(())
+    }
+}
+
+// Check what follows the last record of the first frame, at `offset`: the
+// later frames, the row index against where the records start, the hash of
+// everything read before it, and that nothing else follows them.
+fn check_trailer<R: BufRead>(
+    reader: &mut Hashed<R>,
+    header: &BinaryHeader,
+    mut offset: usize,
+    mut offsets: Vec<u64>,
+    frames: usize,
+    limits: &DecodeLimits
+) -> Result<(), VedError> {
+    let located = |start: usize| {
+        move |error| match error {
+            VedError::UnexpectedEof { offset: end } => VedError::UnexpectedEof { offset: start + end },
+            error => error,
+        }
+    };
+    if header.has_row_index() && header.has_frames() {
+        offsets.push(offset as u64);
+    }
+    let mut record = Vec::new();
+    for frame in 1..frames {
+        let start = read_record(reader, &mut offset, &mut record, limits)?;
+        binary::split_frame(header, frame, &record, start)?;
+    }
+    if header.has_row_index() {
+        let mut index = Vec::new();
+        read_exact_or_eof(reader, offsets.len() * 8, &mut index).map_err(located(offset))?;
+        binary::check_row_index(&index, offset, &offsets)?;
+        offset += index.len();
+    }
+    if let Some(hasher) = reader.hasher.take() {
+        let mut hash = Vec::new();
+        read_exact_or_eof(reader, binary::HASH_LEN, &mut hash).map_err(located(offset))?;
+        if hash != hasher.finish().to_le_bytes() {
+            return Err(VedError::CorruptFile { offset });
+        }
+        offset += hash.len();
+    }
+    if !reader.fill_buf()?.is_empty() {
+        return Err(VedError::BadBinary { offset, message: "trailing data after the last row".to_string() });
+    }
+    Ok(())
+}
+
+// Read the length-prefixed record at `offset` into `record` and move
+// `offset` past it. Returns the offset of the contents of the record, which
+// may take at most `limits.max_memory` bytes.
+pub(crate) fn read_record<R: Read>(
+    reader: &mut R,
+    offset: &mut usize,
+    record: &mut Vec<u8>,
+    limits: &DecodeLimits
+) -> Result<usize, VedError> {
+    let start = *offset;
+    let mut consumed = 0;
+    let len = binary::read_varint(|| {
+        let mut byte = [0];
+        reader.read_exact(&mut byte).map_err(|error| eof_at(error, start + consumed))?;
+        consumed += 1;
+        Ok::<u8, VedError>(byte[0])
+    })?;
+    let len = len.ok_or_else(|| VedError::BadBinary { offset: start, message: "varint overflows 64 bits".to_string() })?;
+    limits.check_memory("record size", len)?;
+    let len = usize::try_from(len).map_err(|_| VedError::UnexpectedEof { offset: start + consumed })?;
+
+    record.clear();
+    read_exact_or_eof(reader, len, record).map_err(|error| match error {
+        VedError::UnexpectedEof { offset } => VedError::UnexpectedEof { offset: start + consumed + offset },
+        error => error,
+    })?;
+    *offset = start + consumed + len;
+    Ok(start + consumed)
+}
+
+// Map an unexpected end of a stream to a VedError at the given offset.
+fn eof_at(error: io::Error, offset: usize) -> VedError {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        VedError::UnexpectedEof { offset }
+    } else {
+        error.into()
+    }
+}
+
+// Append exactly `len` bytes to `out`, without trusting `len` for the allocation.
+pub(crate) fn read_exact_or_eof<R: Read>(reader: &mut R, len: usize, out: &mut Vec<u8>) -> Result<(), VedError> {
+    let start = out.len();
+    reader.take(len as u64).read_to_end(out)?;
+    if out.len() - start != len {
+        return Err(VedError::UnexpectedEof { offset: out.len() });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ DynamicImage, ImageBuffer };
+    use std::io::Cursor;
+    use super::*;
+    use crate::compression::Compression;
+    use crate::decode::decode_bytes;
+
+    const WIDTH: u32 = 23;
+    const HEIGHT: u32 = 150;
+    const PALETTE: [[u8; 4]; 3] = [[0, 0, 0, 255], [255, 0, 0, 255], [10, 20, 30, 128]];
+
+    // RGBA rows with runs, palette colors, rows that repeat an earlier one
+    // and rows that match the one above in places.
+    fn rows() -> Vec<u8> {
+        let pixel = |x: u32, y: u32| match (x / 4 + y) % 5 {
+            0 => PALETTE[0],
+            1 => PALETTE[(y % 3) as usize],
+            _ if y % 7 == 3 => [(x * 9) as u8, 7, (y % 50 * 3) as u8, 255 - (x % 2) as u8],
+            _ => [(x * 9 + y) as u8, (x ^ y) as u8, 40, 255],
+        };
+        (0..HEIGHT).flat_map(|y| (0..WIDTH).flat_map(move |x| pixel(x, y))).collect()
+    }
+
+    fn all_options() -> Vec<EncodeOptions> {
+        let binary = EncodeOptions::default();
+        vec![
+            EncodeOptions { container: Container::Text, ..binary.clone() },
+            binary.clone(),
+            EncodeOptions { compression: Compression::Deflate, ..binary.clone() },
+            EncodeOptions { filtering: Filtering::Adaptive, ..binary.clone() },
+            EncodeOptions { span_rows: true, ..binary.clone() },
+            EncodeOptions { row_index: true, checksums: true, ..binary.clone() },
+            EncodeOptions { span_rows: true, filtering: Filtering::Adaptive, row_index: true, checksums: true, ..binary },
+        ]
+    }
+
+    fn encode(options: &EncodeOptions, rows: &[u8], strip_rows: usize) -> Vec<u8> {
+        let mut encoder = VedEncoder::new(Vec::new(), WIDTH, HEIGHT, true, &PALETTE, options).unwrap();
+        for strip in rows.chunks(WIDTH as usize * 4 * strip_rows) {
+            encoder.write_strip(strip).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn rows_and_strips_give_the_same_bytes() {
+        let rows = rows();
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_raw(WIDTH, HEIGHT, rows.clone()).unwrap());
+        for options in all_options() {
+            let mut encoder = VedEncoder::new(Vec::new(), WIDTH, HEIGHT, true, &PALETTE, &options).unwrap();
+            for row in rows.chunks(WIDTH as usize * 4) {
+                encoder.write_row(row).unwrap();
+            }
+            let by_row = encoder.finish().unwrap();
+            for strip_rows in [7, 64, HEIGHT as usize] {
+                assert_eq!(encode(&options, &rows, strip_rows), by_row, "strips of {} with {:?}", strip_rows, options);
+            }
+            assert_eq!(decode_bytes(&by_row).unwrap().to_rgba8(), img.to_rgba8(), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn finish_needs_every_row() {
+        let rows = rows();
+        for options in all_options() {
+            let mut encoder = VedEncoder::new(Vec::new(), WIDTH, HEIGHT, true, &PALETTE, &options).unwrap();
+            encoder.write_strip(&rows[..rows.len() / 2]).unwrap();
+            let error = encoder.finish().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", options);
+
+            let mut encoder = VedEncoder::new(Vec::new(), WIDTH, HEIGHT, true, &PALETTE, &options).unwrap();
+            encoder.write_strip(&rows).unwrap();
+            let error = encoder.write_row(&rows[..WIDTH as usize * 4]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn read_row_stops_after_the_last_row() {
+        let rows = rows();
+        for options in all_options() {
+            let bytes = encode(&options, &rows, 64);
+            let mut decoder = VedDecoder::new(Cursor::new(&bytes), &DecodeOptions::default()).unwrap();
+            let mut row = vec![0; WIDTH as usize * 4];
+            for expected in rows.chunks(WIDTH as usize * 4) {
+                assert!(decoder.read_row(&mut row).unwrap());
+                assert_eq!(row, expected, "{:?}", options);
+            }
+            assert!(!decoder.read_row(&mut row).unwrap());
+            assert!(!decoder.read_row(&mut row).unwrap());
+            assert!(decoder.finish().unwrap().repairs.is_empty());
+        }
+    }
+
+    #[test]
+    fn finish_checks_the_rows_it_discards() {
+        let rows = rows();
+        for options in all_options() {
+            let bytes = encode(&options, &rows, 64);
+            let mut decoder = VedDecoder::new(Cursor::new(&bytes), &DecodeOptions::default()).unwrap();
+            let mut row = vec![0; WIDTH as usize * 4];
+            decoder.read_row(&mut row).unwrap();
+            assert!(decoder.finish().unwrap().repairs.is_empty(), "{:?}", options);
+
+            // Damage to the rows discarded is still found.
+            let damaged = &bytes[..bytes.len() - 2];
+            let mut decoder = VedDecoder::new(Cursor::new(damaged), &DecodeOptions::default()).unwrap();
+            decoder.read_row(&mut row).unwrap();
+            assert!(decoder.finish().is_err(), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn wide_samples_round_trip_through_rows() {
+        for sample in [SampleType::U16, SampleType::F32] {
+            let rows: Vec<u8> = (0..HEIGHT)
+                .flat_map(|y| (0..WIDTH).flat_map(move |x| [x * 2741 + y, y * 1000, (x ^ y) * 300, 65535 - x]))
+                .flat_map(|value| match sample {
+                    SampleType::F32 => (value as f32 / 700.0 - 3.0).to_ne_bytes().to_vec(),
+                    _ => (value as u16).to_ne_bytes().to_vec(),
+                })
+                .collect();
+            for options in all_options() {
+                let options = EncodeOptions { sample: Some(sample), ..options };
+                let mut encoder = VedEncoder::new(Vec::new(), WIDTH, HEIGHT, true, &[], &options).unwrap();
+                encoder.write_strip(&rows).unwrap();
+                let bytes = encoder.finish().unwrap();
+
+                let mut decoder = VedDecoder::new(Cursor::new(&bytes), &DecodeOptions::default()).unwrap();
+                assert_eq!(decoder.info().sample, sample);
+                let mut row = vec![0; WIDTH as usize * 4 * sample.bytes()];
+                for expected in rows.chunks(row.len()) {
+                    assert!(decoder.read_row(&mut row).unwrap());
+                    assert_eq!(row, expected, "{:?} {:?}", sample, options);
+                }
+                assert!(!decoder.read_row(&mut row).unwrap());
+                decoder.finish().unwrap();
+            }
+        }
+    }
+}